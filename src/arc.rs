@@ -0,0 +1,431 @@
+// What the encoders of the arc control, and where they stand.
+use crate::control::Controls;
+use crate::patch::Patch;
+use crate::{
+    quantize_freq, ArpMode, Division, Error, FmMode, Parameters, Scale, VelCurve, MAX_UNISON,
+    TEMPO,
+};
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+use std::fs;
+
+// Number of encoder pages: pressing an encoder goes to the next page. On the last
+// one, the arc shows the output level instead.
+pub const PAGES: usize = 21;
+pub const METER_PAGE: usize = PAGES - 1;
+// How the position of an encoder, in [0, 64], maps to a frequency.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum FreqCurve {
+    // 0 to 640Hz, 10Hz per LED
+    Linear,
+    // 20Hz to 20kHz, the same interval per LED
+    Exponential,
+}
+
+// The frequency of a position of an encoder, in [0, 64].
+pub fn encoder_to_freq(position: f32, curve: FreqCurve) -> f32 {
+    match curve {
+        FreqCurve::Linear => position * 10.,
+        FreqCurve::Exponential => 20. * 1000f32.powf(position / 64.),
+    }
+}
+
+// The inverse of encoder_to_freq, clamped to the ring.
+pub fn freq_to_encoder(frequency: f32, curve: FreqCurve) -> f32 {
+    let position = match curve {
+        FreqCurve::Linear => frequency / 10.,
+        FreqCurve::Exponential => (frequency.max(20.) / 20.).log(1000.) * 64.,
+    };
+    position.max(0.).min(64.)
+}
+
+// Envelope times, in seconds, for the encoders: the same ratio per LED, as in mapping.toml.
+const ENVELOPE_MIN: f32 = 0.001;
+const ENVELOPE_MAX: f32 = 10.;
+
+// The position of an encoder for an envelope time, clamped to the ring.
+fn seconds_to_encoder(seconds: f32) -> f32 {
+    let position = (seconds.max(ENVELOPE_MIN) / ENVELOPE_MIN).log(ENVELOPE_MAX / ENVELOPE_MIN);
+    (position * 64.).max(0.).min(64.)
+}
+
+// How the position of an encoder is mapped between `min` and `max`.
+#[derive(Debug, Copy, Clone, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MappingCurve {
+    #[default]
+    Linear,
+    // the same ratio per LED, `min` and `max` have the same sign
+    Exponential,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EncoderMapping {
+    page: usize,
+    n: usize,
+    // the name of a parameter, as for --listen
+    parameter: String,
+    // of the operator, for the parameters that have one
+    index: Option<usize>,
+    #[serde(default)]
+    min: f32,
+    #[serde(default)]
+    max: f32,
+    #[serde(default)]
+    curve: MappingCurve,
+    // the value is rounded to a multiple of this, from `min`
+    step: Option<f32>,
+    // instead of `min` and `max`: the ring is split into regions of `width` LEDs, by
+    // default all the same, each sending a value, the last region going to the end
+    #[serde(default)]
+    values: Vec<serde_json::Value>,
+    width: Option<f32>,
+}
+
+impl EncoderMapping {
+    pub fn value(&self, position: f32) -> f32 {
+        let x = position / 64.;
+        match self.curve {
+            MappingCurve::Linear => self.min + x * (self.max - self.min),
+            MappingCurve::Exponential => self.min * (self.max / self.min).powf(x),
+        }
+    }
+    // The inverse of value, clamped to the ring.
+    pub fn position(&self, value: f32) -> f32 {
+        let x = match self.curve {
+            MappingCurve::Linear => (value - self.min) / (self.max - self.min),
+            MappingCurve::Exponential => (value / self.min).ln() / (self.max / self.min).ln(),
+        };
+        (x * 64.).max(0.).min(64.)
+    }
+    fn width(&self) -> f32 {
+        self.width.unwrap_or(64. / self.values.len().max(1) as f32)
+    }
+    pub fn parameter(&self, position: f32) -> Result<Parameters, serde_json::Error> {
+        let mut value = if self.values.is_empty() {
+            let v = match self.step {
+                Some(step) => self.min + ((self.value(position) - self.min) / step).round() * step,
+                None => self.value(position),
+            };
+            serde_json::json!(v)
+        } else {
+            let region = (position / self.width()) as usize;
+            self.values[region.min(self.values.len() - 1)].clone()
+        };
+        if let Some(index) = self.index {
+            value = serde_json::json!([index, value]);
+        }
+        serde_json::from_value(serde_json::json!({ self.parameter.as_str(): value }))
+    }
+}
+
+// What the encoders of the arc control, in TOML, see mapping.toml.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Mapping {
+    encoder: Vec<EncoderMapping>,
+}
+
+impl Mapping {
+    pub fn encoder(&self, page: usize, n: usize) -> Option<&EncoderMapping> {
+        self.encoder.iter().find(|e| e.page == page && e.n == n)
+    }
+}
+
+pub const DEFAULT_MAPPING: &str = include_str!("../mapping.toml");
+
+pub fn parse_mapping(path: &str, contents: &str) -> Result<Mapping, Error> {
+    let error = |e: String| Error::Config(format!("{}: {}", path, e));
+    let mapping: Mapping = toml::from_str(contents).map_err(|e| error(e.to_string()))?;
+    for e in mapping.encoder.iter() {
+        if e.page >= METER_PAGE || e.n >= 4 {
+            return Err(error(format!("no encoder {} on page {}", e.n, e.page)));
+        }
+        if e.curve == MappingCurve::Exponential && e.min * e.max <= 0. {
+            return Err(error(format!(
+                "{}: the min and max of an exponential curve have the same sign",
+                e.parameter
+            )));
+        }
+        if e.values.is_empty() && e.min == e.max {
+            return Err(error(format!("{}: no values, or min and max", e.parameter)));
+        }
+        if e.width() <= 0. || e.step.is_some_and(|step| step <= 0.) {
+            return Err(error(format!("{}: the width and step are positive", e.parameter)));
+        }
+        // each value, or the number
+        for i in 0..e.values.len().max(1) {
+            e.parameter(i as f32 * e.width())
+                .map_err(|_| error(format!("unknown parameter or value {}", e.parameter)))?;
+        }
+    }
+    Ok(mapping)
+}
+
+pub fn load_mapping(path: &str) -> Result<Mapping, Error> {
+    let contents = fs::read_to_string(path).map_err(|e| Error::Io(path.to_string(), e))?;
+    parse_mapping(path, &contents)
+}
+
+// Where the encoders are saved on exit, and restored from on startup.
+pub const ARC_STATE_PATH: &str = "arc-state.toml";
+
+// The positions of the encoders, in [0, 64], per page.
+#[derive(Debug, Serialize, Deserialize)]
+struct ArcState {
+    leds: Vec<[f32; 4]>,
+}
+
+fn load_arc_state(path: &str) -> Result<ArcState, Error> {
+    let contents = fs::read_to_string(path).map_err(|e| Error::Io(path.to_string(), e))?;
+    toml::from_str(&contents).map_err(|e| Error::Config(format!("{}: {}", path, e)))
+}
+
+pub fn save_arc_state(path: &str, leds: &[[f32; 4]]) -> Result<(), Error> {
+    let state = ArcState {
+        leds: leds.to_vec(),
+    };
+    let contents = toml::to_string(&state).map_err(|e| Error::Config(e.to_string()))?;
+    fs::write(path, contents).map_err(|e| Error::Io(path.to_string(), e))
+}
+
+// The positions of the encoders at startup, for `patch` and the encoders of `mapping`,
+// and the tempo of the song if any.
+pub fn initial_leds(patch: &Patch, mapping: &Mapping, tempo: Option<f32>) -> [[f32; 4]; PAGES] {
+    let mut led = [[0.; 4]; PAGES];
+    // where an unmapped encoder starts
+    let mapped = |page, n, value, unmapped| match mapping.encoder(page, n) {
+        Some(e) => e.position(value),
+        None => unmapped,
+    };
+    led[0][0] = freq_to_encoder(patch.carrier_freq, patch.freq_curve);
+    led[0][1] = freq_to_encoder(patch.mod_freq, patch.freq_curve);
+    led[0][2] = seconds_to_encoder(patch.attack);
+    led[0][3] = seconds_to_encoder(patch.release);
+    led[1] = [
+        mapped(1, 0, patch.mod_index, 6.4),
+        mapped(1, 1, patch.cutoff, 64.),
+        mapped(1, 2, patch.resonance, 0.),
+        // the inverse of the half steps of turn_encoder
+        (patch.ratio.unwrap_or(1.) * 8.).max(0.).min(64.),
+    ];
+    led[3] = [3.2, 0., 0., 0.];
+    led[4] = [9.6, 19.2, 0., 0.];
+    led[5] = [32., 0., 16., 0.];
+    led[6] = [(TEMPO - 40.) / 200. * 64., 32., 0., 0.];
+    led[7] = [0., 32., 64., 0.];
+    led[8] = [32., 32., 0., 0.];
+    led[9] = [seconds_to_encoder(patch.decay), patch.hold * 64., patch.sustain * 64., 0.];
+    led[10] = [0., 0., 0., 32.];
+    led[11] = [(440f32 / 20.).log(100.) * 64., 0., 0., 0.];
+    led[12] = [64., 0., 6.4, 6.4];
+    led[13] = [0.8 / 5. * 64., 32., 0., 0.];
+    led[14] = [
+        patch.vel_to_mod * 64.,
+        patch.vel_to_cutoff * 64.,
+        if patch.fm_mode == FmMode::Phase { 64. } else { 0. },
+        patch.sub_level * 64.,
+    ];
+    led[15] = [0., 0., 32., 22.];
+    led[16] = [
+        32.,
+        32.,
+        patch.humanize * 64.,
+        match patch.velocity_curve {
+            VelCurve::Soft => 0.,
+            VelCurve::Linear => 22.,
+            VelCurve::Hard => 44.,
+        },
+    ];
+    led[17] = [
+        if patch.stereo_fm { 64. } else { 0. },
+        patch.stereo_spread * 64.,
+        patch.cross_mod * 64.,
+        patch.mod_env_amount * 64.,
+    ];
+    led[18] = [
+        seconds_to_encoder(patch.mod_env_attack),
+        seconds_to_encoder(patch.mod_env_decay),
+        patch.mod_env_sustain * 64.,
+        seconds_to_encoder(patch.mod_env_release),
+    ];
+    led[19] = [0., 64., 0., 0.];
+    if let Some(tempo) = tempo {
+        led[6][0] = ((tempo - 40.) / 200. * 64.).max(0.).min(64.);
+    }
+    led
+}
+
+// Moves the encoders where they were saved. Those that moved since startup send what
+// they control again, over the patch.
+pub fn restore_arc_state(
+    path: &str,
+    led: &mut [[f32; 4]; PAGES],
+    patch: &mut Patch,
+    mapping: &Mapping,
+    controls: &mut Controls,
+) -> Result<(), Error> {
+    let state = load_arc_state(path)?;
+    for (page, leds) in state.leds.iter().enumerate().take(PAGES) {
+        for n in 0..4 {
+            let position = leds[n].max(0.).min(64.);
+            if position != led[page][n] {
+                led[page][n] = position;
+                turn_encoder(page, n, &led[page], patch, mapping, controls);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Sends what encoder `n` of `page` controls, from the positions of the encoders of the
+// page, `led`.
+pub fn turn_encoder(
+    page: usize,
+    n: usize,
+    led: &[f32; 4],
+    patch: &mut Patch,
+    mapping: &Mapping,
+    controls: &mut Controls,
+) {
+    if let Some(mapped) = mapping.encoder(page, n) {
+        // checked when loading the mapping
+        if let Ok(p) = mapped.parameter(led[n]) {
+            patch.set(p);
+            controls.send(p);
+        }
+        return;
+    }
+    match (page, n) {
+        (0, 0) => {
+            let freq = encoder_to_freq(led[0], patch.freq_curve);
+            patch.carrier_freq = match patch.scale {
+                Some(scale) => quantize_freq(freq, scale, patch.scale_root),
+                None => freq,
+            };
+            controls.send(Parameters::CarrierFreq(patch.carrier_freq));
+        }
+        (0, 1) => {
+            patch.mod_freq = encoder_to_freq(led[1], patch.freq_curve);
+            patch.ratio = None;
+            controls.send(Parameters::ModulationFreq(patch.mod_freq));
+        }
+        (2, 0) | (2, 1) => {
+            let voices = 1 + (led[0] / 64. * (MAX_UNISON - 1) as f32) as usize;
+            let spread = led[1] / 64. * 100.;
+            controls.send(Parameters::Unison(voices, spread));
+        }
+        (6, 1) | (6, 2) => {
+            let pan = led[1] / 32. - 1.;
+            let spread = led[2] / 32.;
+            controls.send(Parameters::Pan(pan, spread));
+        }
+        (7, 3) => {
+            patch.scale = match (led[3] / 13.) as usize {
+                0 => None,
+                1 => Some(Scale::Chromatic),
+                2 => Some(Scale::Major),
+                3 => Some(Scale::Minor),
+                _ => Some(Scale::Pentatonic),
+            };
+        }
+        (10, 2) => {
+            // all the way down is free-running
+            patch.retrigger_phase = if led[2] > 0. {
+                Some((led[2] - 1.) / 63. * 2. * PI)
+            } else {
+                None
+            };
+            controls.send(Parameters::RetriggerPhase(patch.retrigger_phase));
+        }
+        (11, 2) => {
+            let mode = match (led[2] / 13.) as usize {
+                0 => None,
+                1 => Some(ArpMode::Up),
+                2 => Some(ArpMode::Down),
+                3 => Some(ArpMode::UpDown),
+                _ => Some(ArpMode::Random),
+            };
+            controls.send(Parameters::Arpeggiator(mode));
+        }
+        (15, 0) => {
+            // free first, then from the longest division
+            let sync = match (led[0] / 8.) as usize {
+                0 => None,
+                1 => Some(Division::Whole),
+                2 => Some(Division::Half),
+                3 => Some(Division::Quarter),
+                4 => Some(Division::QuarterTriplet),
+                5 => Some(Division::Eighth),
+                6 => Some(Division::EighthTriplet),
+                _ => Some(Division::Sixteenth),
+            };
+            controls.send(Parameters::LfoSync(sync));
+        }
+        (19, 2) => {
+            // free first, then from the longest division
+            let sync = match (led[2] / 8.) as usize {
+                0 => None,
+                1 => Some(Division::Half),
+                2 => Some(Division::DottedQuarter),
+                3 => Some(Division::Quarter),
+                4 => Some(Division::DottedEighth),
+                5 => Some(Division::Eighth),
+                6 => Some(Division::EighthTriplet),
+                _ => Some(Division::Sixteenth),
+            };
+            controls.send(Parameters::DelaySync(sync));
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CurveShape;
+
+    #[test]
+    fn positions_invert_the_mapping() {
+        let mapping = parse_mapping("mapping.toml", DEFAULT_MAPPING).unwrap();
+        for e in mapping.encoder.iter().filter(|e| e.values.is_empty() && e.step.is_none()) {
+            for &position in &[0., 10., 32., 64.] {
+                let back = e.position(e.value(position));
+                assert!((back - position).abs() < 1e-3, "{}: {} {}", e.parameter, position, back);
+            }
+        }
+        let patch = Patch {
+            mod_index: 2.5,
+            cutoff: 632.,
+            resonance: 0.5,
+            ratio: Some(1.5),
+            ..Patch::default()
+        };
+        let led = initial_leds(&patch, &mapping, None);
+        assert!((led[1][0] - 16.).abs() < 1e-3);
+        assert!((led[1][1] - 32.).abs() < 0.1);
+        assert!((led[1][2] - 32.).abs() < 1e-3);
+        assert_eq!(led[1][3], 12.);
+    }
+
+
+    #[test]
+    fn regions_and_steps_match_the_built_in_encoders() {
+        let mapping = parse_mapping("mapping.toml", DEFAULT_MAPPING).unwrap();
+        let parameter = |page, n, position| mapping.encoder(page, n).unwrap().parameter(position);
+        // (led / 4).round() / 2
+        assert!(matches!(parameter(1, 3, 13.), Ok(Parameters::Ratio(r)) if r == 1.5));
+        assert!(matches!(parameter(5, 2, 64.), Ok(Parameters::OperatorRatio(2, r)) if r == 8.));
+        // (led / 22) as usize
+        assert!(matches!(parameter(2, 3, 21.9), Ok(Parameters::Curve(CurveShape::Linear))));
+        let power = parameter(2, 3, 22.);
+        assert!(matches!(power, Ok(Parameters::Curve(CurveShape::Power(p))) if p == 2.));
+        assert!(matches!(parameter(2, 3, 64.), Ok(Parameters::Curve(CurveShape::Exponential))));
+        // divisions[((led / 11) as usize).min(5)]
+        assert!(matches!(parameter(11, 3, 54.9), Ok(Parameters::ArpRate(6))));
+        assert!(matches!(parameter(11, 3, 64.), Ok(Parameters::ArpRate(8))));
+        // led >= 32
+        assert!(matches!(parameter(15, 1, 31.9), Ok(Parameters::Metronome(false))));
+        assert!(matches!(parameter(15, 1, 32.), Ok(Parameters::Metronome(true))));
+    }
+}
@@ -0,0 +1,735 @@
+// Parameters sent to the audio thread from the other threads, the control thread of
+// the monome devices, MIDI, OSC and the remote control: the continuous ones through a
+// snapshot, the others through a queue.
+use crate::arc::{encoder_to_freq, FreqCurve};
+use crate::{midi_to_freq, Error, Parameters};
+use audio_clock::ClockConsumer;
+use crossbeam::queue::ArrayQueue;
+use midir::{MidiInput, MidiInputConnection};
+use rosc::{OscMessage, OscPacket, OscType};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// The tempo of audio_clock is fixed when it is created, so the beat position is
+// accumulated by the sequencer, and published here for the other threads.
+#[derive(Clone)]
+pub struct BeatClock {
+    beat: Arc<AtomicU64>,
+}
+
+impl BeatClock {
+    pub fn new() -> BeatClock {
+        BeatClock {
+            beat: Arc::new(AtomicU64::new(0f64.to_bits())),
+        }
+    }
+    pub fn beat(&self) -> f64 {
+        f64::from_bits(self.beat.load(Ordering::Relaxed))
+    }
+    pub fn set_beat(&self, beat: f64) {
+        self.beat.store(beat.to_bits(), Ordering::Relaxed);
+    }
+}
+
+impl Default for BeatClock {
+    fn default() -> BeatClock {
+        BeatClock::new()
+    }
+}
+
+// The continuous parameters, that go through the ParamSnapshot instead of the queue.
+pub const SNAPSHOT_SLOTS: usize = 57;
+pub const SNAPSHOT: [fn(f32) -> Parameters; SNAPSHOT_SLOTS] = [
+    Parameters::CarrierFreq,
+    Parameters::ModulationFreq,
+    Parameters::Ratio,
+    Parameters::ModIndex,
+    Parameters::Feedback,
+    Parameters::Attack,
+    Parameters::Release,
+    Parameters::Cutoff,
+    Parameters::Resonance,
+    Parameters::LfoRate,
+    Parameters::LfoDepth,
+    Parameters::DelayTime,
+    Parameters::DelayFeedback,
+    Parameters::DelayMix,
+    Parameters::Drive,
+    Parameters::MasterGain,
+    Parameters::Tempo,
+    Parameters::Glide,
+    Parameters::KeyTracking,
+    Parameters::WetAmount,
+    Parameters::ReverbRoomSize,
+    Parameters::ReverbDamping,
+    Parameters::ReverbMix,
+    Parameters::Decay,
+    Parameters::Hold,
+    Parameters::Sustain,
+    Parameters::AutoWah,
+    Parameters::AutoWahAttack,
+    Parameters::AutoWahRelease,
+    Parameters::Bits,
+    Parameters::Downsample,
+    Parameters::Width,
+    Parameters::RingModFreq,
+    Parameters::RingModMix,
+    Parameters::AlgoMorph,
+    Parameters::CompThreshold,
+    Parameters::CompRatio,
+    Parameters::CompAttack,
+    Parameters::CompRelease,
+    Parameters::ChorusRate,
+    Parameters::ChorusDepth,
+    Parameters::ChorusMix,
+    Parameters::VelToMod,
+    Parameters::VelToCutoff,
+    Parameters::SubLevel,
+    Parameters::MetronomeLevel,
+    Parameters::TransientAttack,
+    Parameters::TransientSustain,
+    Parameters::Humanize,
+    Parameters::StereoSpread,
+    Parameters::CrossMod,
+    Parameters::ModEnvAttack,
+    Parameters::ModEnvDecay,
+    Parameters::ModEnvSustain,
+    Parameters::ModEnvRelease,
+    Parameters::ModEnvAmount,
+    Parameters::ShaperGain,
+];
+
+pub fn snapshot_parameter(slot: usize, v: f32) -> Parameters {
+    SNAPSHOT[slot](v)
+}
+
+// The snapshot slot and value of `p`, if it is a continuous parameter.
+fn snapshot_slot(p: Parameters) -> Option<(usize, f32)> {
+    let v = match p {
+        Parameters::CarrierFreq(v)
+        | Parameters::ModulationFreq(v)
+        | Parameters::Ratio(v)
+        | Parameters::ModIndex(v)
+        | Parameters::Feedback(v)
+        | Parameters::Attack(v)
+        | Parameters::Release(v)
+        | Parameters::Cutoff(v)
+        | Parameters::Resonance(v)
+        | Parameters::LfoRate(v)
+        | Parameters::LfoDepth(v)
+        | Parameters::DelayTime(v)
+        | Parameters::DelayFeedback(v)
+        | Parameters::DelayMix(v)
+        | Parameters::Drive(v)
+        | Parameters::MasterGain(v)
+        | Parameters::Tempo(v)
+        | Parameters::Glide(v)
+        | Parameters::KeyTracking(v)
+        | Parameters::WetAmount(v)
+        | Parameters::ReverbRoomSize(v)
+        | Parameters::ReverbDamping(v)
+        | Parameters::ReverbMix(v)
+        | Parameters::Decay(v)
+        | Parameters::Hold(v)
+        | Parameters::Sustain(v)
+        | Parameters::AutoWah(v)
+        | Parameters::AutoWahAttack(v)
+        | Parameters::AutoWahRelease(v)
+        | Parameters::Bits(v)
+        | Parameters::Downsample(v)
+        | Parameters::Width(v)
+        | Parameters::RingModFreq(v)
+        | Parameters::RingModMix(v)
+        | Parameters::AlgoMorph(v)
+        | Parameters::CompThreshold(v)
+        | Parameters::CompRatio(v)
+        | Parameters::CompAttack(v)
+        | Parameters::CompRelease(v)
+        | Parameters::ChorusRate(v)
+        | Parameters::ChorusDepth(v)
+        | Parameters::ChorusMix(v)
+        | Parameters::VelToMod(v)
+        | Parameters::VelToCutoff(v)
+        | Parameters::SubLevel(v)
+        | Parameters::MetronomeLevel(v)
+        | Parameters::TransientAttack(v)
+        | Parameters::TransientSustain(v)
+        | Parameters::Humanize(v)
+        | Parameters::StereoSpread(v)
+        | Parameters::CrossMod(v)
+        | Parameters::ModEnvAttack(v)
+        | Parameters::ModEnvDecay(v)
+        | Parameters::ModEnvSustain(v)
+        | Parameters::ModEnvRelease(v)
+        | Parameters::ModEnvAmount(v)
+        | Parameters::ShaperGain(v) => v,
+        _ => return None,
+    };
+    let discriminant = std::mem::discriminant(&p);
+    SNAPSHOT
+        .iter()
+        .position(|f| std::mem::discriminant(&f(0.)) == discriminant)
+        .map(|slot| (slot, v))
+}
+
+// Latest value of all the continuous parameters, NaN when never set. A single
+// control thread writes them all into the back buffer, and then publishes it. The
+// audio thread reads the front buffer in one go: if it has been published over in
+// the meantime, the read is discarded and retried on the next callback. Nothing
+// blocks, and nothing is lost, since only the latest value of a parameter matters.
+pub struct ParamSnapshot {
+    buffers: [Vec<AtomicU32>; 2],
+    generation: AtomicUsize,
+}
+
+impl ParamSnapshot {
+    pub fn new() -> ParamSnapshot {
+        let unset = || {
+            (0..SNAPSHOT_SLOTS)
+                .map(|_| AtomicU32::new(f32::NAN.to_bits()))
+                .collect()
+        };
+        ParamSnapshot {
+            buffers: [unset(), unset()],
+            generation: AtomicUsize::new(0),
+        }
+    }
+    pub fn publish(&self, values: &[f32; SNAPSHOT_SLOTS]) {
+        let generation = self.generation.load(Ordering::Relaxed);
+        // a reader validating a read of the back buffer sees the previous generation
+        fence(Ordering::Release);
+        let back = &self.buffers[(generation + 1) % 2];
+        for (slot, v) in back.iter().zip(values.iter()) {
+            slot.store(v.to_bits(), Ordering::Relaxed);
+        }
+        self.generation.store(generation + 1, Ordering::Release);
+    }
+    // true if `values` now holds a complete snapshot
+    pub fn read(&self, values: &mut [f32; SNAPSHOT_SLOTS]) -> bool {
+        let generation = self.generation.load(Ordering::Acquire);
+        let front = &self.buffers[generation % 2];
+        let mut read = [0.; SNAPSHOT_SLOTS];
+        for (v, slot) in read.iter_mut().zip(front.iter()) {
+            *v = f32::from_bits(slot.load(Ordering::Relaxed));
+        }
+        fence(Ordering::Acquire);
+        if self.generation.load(Ordering::Relaxed) != generation {
+            return false;
+        }
+        *values = read;
+        true
+    }
+}
+
+impl Default for ParamSnapshot {
+    fn default() -> ParamSnapshot {
+        ParamSnapshot::new()
+    }
+}
+
+// A parameter change, and the frame at which the audio thread applies it, 0 being as
+// soon as possible.
+pub type Event = (usize, Parameters);
+
+// Sends parameters from the control thread to the audio thread: continuous
+// parameters through the snapshot, events such as notes through the queue, in order.
+pub struct Controls {
+    queue: Arc<ArrayQueue<Event>>,
+    snapshot: Arc<ParamSnapshot>,
+    values: [f32; SNAPSHOT_SLOTS],
+    state: Arc<ParameterState>,
+    // events are scheduled this many frames ahead of the clock, so that they land at
+    // the same frame into the callback whenever they are sent
+    clock: ClockConsumer,
+    ahead: usize,
+    automation: Automation,
+    // changes dropped because the queue was full, since the last take_dropped
+    dropped: usize,
+}
+
+// Where the recording of the parameters sent stands.
+enum AutomationState {
+    Idle,
+    Recording { start: usize },
+    // `next` is the next event to send of the loop that started at `start`
+    Playing { start: usize, length: usize, next: usize },
+}
+
+// Parameters sent while recording, timestamped in frames since the recording
+// started, played back in a loop as long as the recording.
+pub struct Automation {
+    events: Vec<Event>,
+    length: usize,
+    state: AutomationState,
+}
+
+impl Automation {
+    pub fn new() -> Automation {
+        Automation {
+            events: Vec::new(),
+            length: 0,
+            state: AutomationState::Idle,
+        }
+    }
+    pub fn is_recording(&self) -> bool {
+        matches!(self.state, AutomationState::Recording { .. })
+    }
+    pub fn is_playing(&self) -> bool {
+        matches!(self.state, AutomationState::Playing { .. })
+    }
+}
+
+impl Default for Automation {
+    fn default() -> Automation {
+        Automation::new()
+    }
+}
+
+impl Controls {
+    pub fn new(
+        queue: Arc<ArrayQueue<Event>>,
+        snapshot: Arc<ParamSnapshot>,
+        state: Arc<ParameterState>,
+        clock: ClockConsumer,
+        ahead: usize,
+    ) -> Controls {
+        Controls {
+            queue,
+            snapshot,
+            values: [f32::NAN; SNAPSHOT_SLOTS],
+            state,
+            clock,
+            ahead,
+            automation: Automation::new(),
+            dropped: 0,
+        }
+    }
+    // To the audio thread, to be applied at frame `target`.
+    pub fn push(&self, target: usize, p: Parameters) -> Result<(), Error> {
+        self.queue.push((target, p)).map_err(|_| Error::QueueFull(p))
+    }
+    pub fn send(&mut self, p: Parameters) {
+        if let AutomationState::Recording { start } = self.automation.state {
+            let offset = self.clock.raw_frames().saturating_sub(start);
+            self.automation.events.push((offset, p));
+        }
+        self.state.record(p);
+        match snapshot_slot(p) {
+            Some((slot, v)) => {
+                self.values[slot] = v;
+                self.snapshot.publish(&self.values);
+            }
+            None => {
+                let target = self.clock.raw_frames() + self.ahead;
+                if self.push(target, p).is_err() {
+                    self.dropped += 1;
+                }
+            }
+        }
+    }
+    // How many changes were dropped since the last call, because the audio thread
+    // doesn't keep up.
+    pub fn take_dropped(&mut self) -> usize {
+        std::mem::replace(&mut self.dropped, 0)
+    }
+    // Starts recording, forgetting the previous recording.
+    pub fn start_recording(&mut self) {
+        self.automation.events.clear();
+        self.automation.state = AutomationState::Recording {
+            start: self.clock.raw_frames(),
+        };
+    }
+    // Stops recording, and loops what has been recorded.
+    pub fn stop_recording(&mut self) {
+        if let AutomationState::Recording { start } = self.automation.state {
+            let now = self.clock.raw_frames();
+            self.automation.length = now.saturating_sub(start);
+            self.automation.state = AutomationState::Idle;
+            self.start_playback();
+        }
+    }
+    pub fn start_playback(&mut self) {
+        if self.automation.events.is_empty() || self.automation.length == 0 {
+            return;
+        }
+        self.automation.state = AutomationState::Playing {
+            start: self.clock.raw_frames(),
+            length: self.automation.length,
+            next: 0,
+        };
+    }
+    pub fn stop_playback(&mut self) {
+        if self.automation.is_playing() {
+            self.automation.state = AutomationState::Idle;
+        }
+    }
+    pub fn automation(&self) -> &Automation {
+        &self.automation
+    }
+    // Sends the recorded events that are due, through the queue, at the frame they
+    // were recorded at into the loop. To call regularly.
+    pub fn play_automation(&mut self) {
+        let (mut start, length, mut next) = match self.automation.state {
+            AutomationState::Playing {
+                start,
+                length,
+                next,
+            } => (start, length, next),
+            _ => return,
+        };
+        let now = self.clock.raw_frames();
+        loop {
+            while next < self.automation.events.len()
+                && start + self.automation.events[next].0 <= now
+            {
+                let (offset, p) = self.automation.events[next];
+                self.state.record(p);
+                if self.push(start + offset + self.ahead, p).is_err() {
+                    self.dropped += 1;
+                }
+                next += 1;
+            }
+            if next < self.automation.events.len() || now < start + length {
+                break;
+            }
+            start += length;
+            next = 0;
+        }
+        self.automation.state = AutomationState::Playing {
+            start,
+            length,
+            next,
+        };
+    }
+}
+
+// Last value sent of each parameter, for remote monitoring. Never touched by the
+// audio thread.
+pub struct ParameterState {
+    values: Mutex<Vec<Parameters>>,
+}
+
+impl ParameterState {
+    pub fn new() -> ParameterState {
+        ParameterState {
+            values: Mutex::new(Vec::new()),
+        }
+    }
+    pub fn record(&self, p: Parameters) {
+        // events are not state
+        match p {
+            Parameters::NoteOn(..)
+            | Parameters::NoteOff(..)
+            | Parameters::ToggleStep(..)
+            | Parameters::SampleOn(..)
+            | Parameters::Panic => {
+                return;
+            }
+            _ => {}
+        }
+        let same = |other: &Parameters| match (p, *other) {
+            (Parameters::OperatorRatio(a, _), Parameters::OperatorRatio(b, _))
+            | (Parameters::OperatorLevel(a, _), Parameters::OperatorLevel(b, _))
+            | (Parameters::SamplePitch(a, _), Parameters::SamplePitch(b, _)) => a == b,
+            (Parameters::Breakpoint(a, ..), Parameters::Breakpoint(b, ..)) => a == b,
+            (Parameters::PitchBend(a, _), Parameters::PitchBend(b, _)) => a == b,
+            _ => std::mem::discriminant(&p) == std::mem::discriminant(other),
+        };
+        let mut values = self.values.lock().unwrap();
+        match values.iter().position(same) {
+            Some(i) => values[i] = p,
+            None => values.push(p),
+        }
+    }
+    pub fn to_json(&self) -> String {
+        let values = self.values.lock().unwrap();
+        serde_json::to_string(&*values).unwrap_or_default()
+    }
+}
+
+impl Default for ParameterState {
+    fn default() -> ParameterState {
+        ParameterState::new()
+    }
+}
+
+// Remote control: each line received on a connection is either `"get"`, answered
+// with the current state as a JSON array of parameters, or a parameter in JSON, e.g.
+// `{"Cutoff":1000.0}`, that is sent to the audio thread. Each connection is handled
+// on its own thread.
+pub fn listen(
+    addr: &str,
+    q: Arc<ArrayQueue<Event>>,
+    state: Arc<ParameterState>,
+) -> Result<(), Error> {
+    let listener = TcpListener::bind(addr).map_err(|e| Error::Io(addr.to_string(), e))?;
+    println!("Listening on {}", addr);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            if let Ok(stream) = stream {
+                let q = q.clone();
+                let state = state.clone();
+                thread::spawn(move || serve(stream, q, state));
+            }
+        }
+    });
+    Ok(())
+}
+
+fn serve(stream: TcpStream, q: Arc<ArrayQueue<Event>>, state: Arc<ParameterState>) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        let line = line.trim();
+        let reply = if line == "\"get\"" || line == "get" {
+            state.to_json()
+        } else {
+            match serde_json::from_str::<Parameters>(line) {
+                Ok(p) => {
+                    state.record(p);
+                    match q.push((0, p)) {
+                        Ok(()) => "\"ok\"".to_string(),
+                        Err(_) => "{\"error\":\"queue full\"}".to_string(),
+                    }
+                }
+                Err(e) => serde_json::json!({ "error": e.to_string() }).to_string(),
+            }
+        };
+        if writeln!(writer, "{}", reply).is_err() {
+            return;
+        }
+    }
+}
+
+// [0, 127] -> [0, 1]
+fn midi_value_to_unit(value: u8) -> f32 {
+    value as f32 / 127.
+}
+
+// semitones, up and down, at the ends of the pitch wheel
+const PITCH_BEND_RANGE: f32 = 2.;
+// The notes of this channel, 10 counting from 1 as for drums, play the samples, the
+// first one from SAMPLE_ROOT up.
+pub const SAMPLE_CHANNEL: u8 = 9;
+pub const SAMPLE_ROOT: u8 = 36;
+
+pub fn parse_midi(message: &[u8]) -> Option<Parameters> {
+    if message.len() < 3 {
+        return None;
+    }
+    let (status, data1, data2) = (message[0] & 0xf0, message[1], message[2]);
+    let channel = message[0] & 0x0f;
+    match status {
+        0x90 if channel == SAMPLE_CHANNEL && data2 != 0 && data1 >= SAMPLE_ROOT => Some(
+            Parameters::SampleOn((data1 - SAMPLE_ROOT) as usize, midi_value_to_unit(data2)),
+        ),
+        // the samples play until their end
+        0x80 | 0x90 if channel == SAMPLE_CHANNEL => None,
+        // a note on with a velocity of 0 is a note off
+        0x90 if data2 != 0 => Some(Parameters::NoteOn(
+            channel,
+            midi_to_freq(data1),
+            midi_value_to_unit(data2),
+        )),
+        0x80 | 0x90 => Some(Parameters::NoteOff(midi_to_freq(data1))),
+        // 14 bits, centered on 8192
+        0xe0 => {
+            let bend = ((data2 as i32) << 7 | data1 as i32) - 8192;
+            Some(Parameters::PitchBend(channel, bend as f32 / 8192. * PITCH_BEND_RANGE))
+        }
+        0xb0 => {
+            let v = midi_value_to_unit(data2);
+            match data1 {
+                1 => Some(Parameters::ModIndex(v * 10.)),
+                71 => Some(Parameters::Resonance(v)),
+                72 => Some(Parameters::Release(v)),
+                73 => Some(Parameters::Attack(v)),
+                // 20Hz to 20kHz, as the encoders
+                74 => Some(Parameters::Cutoff(encoder_to_freq(v * 64., FreqCurve::Exponential))),
+                // All Notes Off
+                123 => Some(Parameters::Panic),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+// Connects to the first MIDI input port, if any, and forwards its messages to `q`.
+pub fn open_midi(q: Arc<ArrayQueue<Event>>) -> Option<MidiInputConnection<()>> {
+    let midi_in = match MidiInput::new("redh") {
+        Ok(midi_in) => midi_in,
+        Err(e) => {
+            println!("MIDI unavailable: {}", e);
+            return None;
+        }
+    };
+    if midi_in.port_count() == 0 {
+        println!("No MIDI input port");
+        return None;
+    }
+    let name = midi_in.port_name(0).unwrap_or_default();
+    let connection = midi_in.connect(
+        0,
+        "redh-in",
+        move |_, message, _| {
+            if let Some(p) = parse_midi(message) {
+                // MIDI events are dropped if the audio thread is not keeping up
+                let _ = q.push((0, p));
+            }
+        },
+        (),
+    );
+    match connection {
+        Ok(connection) => {
+            println!("MIDI input: {}", name);
+            Some(connection)
+        }
+        Err(e) => {
+            println!("Could not open MIDI input {}: {}", name, e);
+            None
+        }
+    }
+}
+
+// The OSC messages understood all start with this.
+pub const OSC_PREFIX: &str = "/synth/";
+
+// An OSC argument as JSON, if it can be one.
+fn osc_to_json(arg: &OscType) -> Option<serde_json::Value> {
+    match *arg {
+        OscType::Int(v) => Some(v.into()),
+        OscType::Long(v) => Some(v.into()),
+        OscType::Float(v) => Some(v.into()),
+        OscType::Double(v) => Some(v.into()),
+        OscType::Bool(v) => Some(v.into()),
+        OscType::String(ref v) => Some(v.as_str().into()),
+        _ => None,
+    }
+}
+
+// `/synth/note <note> <velocity>`, both MIDI-like, a velocity of 0 being a note off, or
+// `/synth/carrier <frequency>`, or else the rest of the address names a parameter in
+// snake case, its arguments being those of the parameter, e.g. `/synth/mod_index 2.5`,
+// `/synth/operator_ratio 1 3.0` or `/synth/panic`.
+pub fn parse_osc(message: &OscMessage) -> Result<Parameters, Error> {
+    let name = match message.addr.strip_prefix(OSC_PREFIX) {
+        Some(name) => name,
+        None => return Err(Error::Config(format!("not under {}", OSC_PREFIX))),
+    };
+    let args = message
+        .args
+        .iter()
+        .map(|a| {
+            osc_to_json(a).ok_or_else(|| Error::Config(format!("unsupported argument {:?}", a)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if name == "note" {
+        let number = |i: usize| args.get(i).and_then(|a| a.as_f64()).map(|v| v as f32);
+        let (note, velocity) = match (number(0), number(1)) {
+            (Some(note), velocity) => (note.max(0.).min(127.), velocity.unwrap_or(127.)),
+            _ => return Err(Error::Config("expected a note and a velocity".to_string())),
+        };
+        let frequency = midi_to_freq(note.round() as u8);
+        return Ok(if velocity > 0. {
+            Parameters::NoteOn(0, frequency, (velocity / 127.).min(1.))
+        } else {
+            Parameters::NoteOff(frequency)
+        });
+    }
+    let variant = if name == "carrier" {
+        "CarrierFreq".to_string()
+    } else {
+        name.split('_')
+            .map(|word| {
+                let mut chars = word.chars();
+                match chars.next() {
+                    Some(first) => first.to_uppercase().chain(chars).collect(),
+                    None => String::new(),
+                }
+            })
+            .collect::<String>()
+    };
+    let value = match args.len() {
+        0 => serde_json::Value::String(variant),
+        1 => serde_json::json!({ variant: args[0] }),
+        _ => serde_json::json!({ variant: args }),
+    };
+    serde_json::from_value(value).map_err(|e| Error::Config(e.to_string()))
+}
+
+// Messages of bundles are sent as they arrive, regardless of their time tag.
+fn osc_messages(packet: OscPacket, messages: &mut Vec<OscMessage>) {
+    match packet {
+        OscPacket::Message(message) => messages.push(message),
+        OscPacket::Bundle(bundle) => {
+            for packet in bundle.content {
+                osc_messages(packet, messages);
+            }
+        }
+    }
+}
+
+// Receives OSC over UDP on `port`, on its own thread, and sends the parameters to the
+// audio thread as the remote control does.
+pub fn open_osc(
+    port: u16,
+    q: Arc<ArrayQueue<Event>>,
+    state: Arc<ParameterState>,
+) -> Result<(), Error> {
+    let socket =
+        UdpSocket::bind(("0.0.0.0", port)).map_err(|e| Error::Io(port.to_string(), e))?;
+    println!("OSC input on port {}", port);
+    thread::spawn(move || {
+        let mut buffer = [0u8; rosc::decoder::MTU];
+        let mut messages = Vec::new();
+        loop {
+            let size = match socket.recv_from(&mut buffer) {
+                Ok((size, _)) => size,
+                Err(_) => continue,
+            };
+            match rosc::decoder::decode_udp(&buffer[..size]) {
+                Ok((_, packet)) => osc_messages(packet, &mut messages),
+                Err(e) => {
+                    println!("OSC: {:?}", e);
+                    continue;
+                }
+            }
+            for message in messages.drain(..) {
+                match parse_osc(&message) {
+                    Ok(p) => {
+                        state.record(p);
+                        // dropped if the audio thread is not keeping up, as MIDI
+                        let _ = q.push((0, p));
+                    }
+                    Err(e) => println!("OSC {}: {}", message.addr, e),
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cutoff_cc_covers_the_range_of_the_encoders() {
+        let cutoff = |value| match parse_midi(&[0xb0, 74, value]) {
+            Some(Parameters::Cutoff(v)) => v,
+            p => panic!("{:?}", p),
+        };
+        assert!((cutoff(0) - 20.).abs() < 1e-3);
+        assert!((cutoff(127) - 20000.).abs() < 0.1);
+    }
+}
@@ -0,0 +1,4515 @@
+// The synthesizer, without the audio device and the controllers, driven through
+// Engine, and what drives it without depending on the audio device or the monome
+// devices: the controls, the encoders of the arc, patches and songs, and recording.
+use cubeb::StereoFrame;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+use std::fs;
+use std::sync::Arc;
+
+pub mod arc;
+pub mod control;
+pub mod patch;
+pub mod record;
+
+pub const TEMPO: f32 = 128.;
+
+pub type Frame = StereoFrame<f32>;
+
+// What can go wrong, setting up the synth or running it.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    // bad arguments, patch, song, mapping or message
+    #[error("{0}")]
+    Config(String),
+    // a file that could not be read or written, or a port that could not be opened
+    #[error("{0}: {1}")]
+    Io(String, #[source] std::io::Error),
+    // a sample or a table that could not be read, or a recording, with its path
+    #[error("{0}: {1}")]
+    Wav(String, #[source] hound::Error),
+    #[error("could not initialize the audio ({0})")]
+    AudioInit(cubeb::Error),
+    #[error("could not create the audio stream ({0})")]
+    StreamBuild(cubeb::Error),
+    #[error("audio stream error ({0})")]
+    Stream(cubeb::Error),
+    #[error("monome: {0}")]
+    MonomeConnect(String),
+    #[error("parameter queue full, dropping {0:?}")]
+    QueueFull(Parameters),
+    #[error("could not install the Ctrl-C handler ({0})")]
+    Signal(#[from] ctrlc::Error),
+}
+
+// Shape of the envelope segments, [0,1] -> [0,1].
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub enum CurveShape {
+    Linear,
+    Exponential,
+    Power(f32),
+}
+
+impl CurveShape {
+    fn apply(self, t: f32) -> f32 {
+        match self {
+            CurveShape::Linear => t,
+            CurveShape::Exponential => ((5. * t).exp() - 1.) / ((5. as f32).exp() - 1.),
+            CurveShape::Power(p) => t.powf(p),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ADSR {
+    start_time: usize,
+    attack: usize,
+    // seconds, as set, and scaled by `attack_scale` into `attack`
+    attack_seconds: f32,
+    attack_scale: f32,
+    decay: usize,
+    hold: usize,
+    release: usize,
+    sample_rate: f32,
+    sustain: f32,
+    // when gated, the sustain lasts until note_off, instead of `hold`
+    gated: bool,
+    release_time: Option<usize>,
+    release_level: f32,
+    curve: CurveShape,
+    // [0, 1], scales the output
+    velocity: f32,
+    // level the attack starts from, so that retriggering doesn't click
+    start_level: f32,
+    // held at the sustain level, whatever the stage, until the gate is closed
+    drone: bool,
+}
+
+impl ADSR {
+    pub fn new(sample_rate: f32) -> ADSR {
+        ADSR {
+            start_time: 0,
+            attack: 0,
+            attack_seconds: 0.,
+            attack_scale: 1.,
+            decay: 0,
+            hold: 0,
+            release: (sample_rate / 100.) as usize,
+            sample_rate,
+            sustain: 1.0,
+            gated: false,
+            // silent until triggered
+            release_time: Some(0),
+            release_level: 0.,
+            curve: CurveShape::Power(2.),
+            velocity: 1.,
+            start_level: 0.,
+            drone: false,
+        }
+    }
+    pub fn render(&mut self, t: usize) -> f32 {
+        return self.velocity * self.level(t);
+    }
+    fn level(&self, t: usize) -> f32 {
+        // shaping: [0,1] -> [0,1]
+        let curve = self.curve;
+        let f = |t: f32| -> f32 {
+            return curve.apply(t);
+        };
+        if self.drone {
+            return self.sustain;
+        }
+        if t < self.start_time || self.is_finished(t) {
+            return 0.0;
+        }
+        let release_start = self.release_start();
+        if t >= release_start {
+            let level = match self.release_time {
+                Some(_) => self.release_level,
+                None => self.sustain,
+            };
+            return level - level * f((t - release_start) as f32 / (self.release) as f32);
+        }
+        let mut t = t - self.start_time;
+        if t < self.attack {
+            return self.start_level + (1. - self.start_level) * f(t as f32 / self.attack as f32);
+        }
+        t -= self.attack;
+        if t < self.decay {
+            return 1.0 - (1.0 - self.sustain) * f((t as f32) / (self.decay) as f32);
+        }
+        return self.sustain;
+    }
+    pub fn set_attack(&mut self, attack: f32) {
+        self.attack_seconds = attack;
+        self.attack = self.s2f(self.attack_seconds * self.attack_scale);
+    }
+    // Multiplies the attack time, e.g. to vary it from note to note.
+    pub fn set_attack_scale(&mut self, scale: f32) {
+        self.attack_scale = scale.max(0.);
+        self.attack = self.s2f(self.attack_seconds * self.attack_scale);
+    }
+    pub fn set_decay(&mut self, decay: f32) {
+        self.decay = self.s2f(decay);
+    }
+    pub fn set_hold(&mut self, hold: f32) {
+        self.hold = self.s2f(hold);
+    }
+    pub fn set_sustain(&mut self, sustain: f32) {
+        self.sustain = sustain;
+    }
+    pub fn set_release(&mut self, release: f32) {
+        self.release = self.s2f(release);
+    }
+    pub fn set_curve(&mut self, curve: CurveShape) {
+        self.curve = curve;
+    }
+    // One-shot: attack, decay, hold, then release. The attack starts from the current
+    // output, if the envelope is still running.
+    pub fn trigger_with_velocity(&mut self, time: usize, velocity: f32) {
+        let current = self.render(time);
+        self.start_time = time;
+        self.gated = false;
+        self.release_time = None;
+        self.velocity = velocity.max(0.).min(1.);
+        self.start_level = if self.velocity > 0. {
+            (current / self.velocity).min(1.)
+        } else {
+            0.
+        };
+    }
+    // Hold the sustain level until note_off.
+    pub fn note_on(&mut self, time: usize, velocity: f32) {
+        self.trigger_with_velocity(time, velocity);
+        self.gated = true;
+    }
+    // Release from the current level.
+    pub fn note_off(&mut self, time: usize) {
+        if self.drone || self.release_time.is_some() || time >= self.release_start() {
+            return;
+        }
+        self.release_level = self.level(time);
+        self.release_time = Some(time);
+    }
+    // Silent, so that the next attack starts from 0.
+    pub fn silence(&mut self) {
+        self.start_time = 0;
+        self.release_time = Some(0);
+        self.release_level = 0.;
+    }
+    pub fn is_finished(&self, t: usize) -> bool {
+        !self.drone && self.release_start().saturating_add(self.release) <= t
+    }
+    // Open, the output stays at the sustain level. Closed, it releases from there.
+    pub fn set_gate(&mut self, gate: bool, time: usize) {
+        if gate == self.drone {
+            return;
+        }
+        self.drone = gate;
+        if !gate {
+            self.release_level = self.sustain;
+            self.release_time = Some(time);
+        }
+    }
+    fn release_start(&self) -> usize {
+        match self.release_time {
+            Some(time) => time,
+            None if self.gated => usize::MAX,
+            None => self.start_time + self.attack + self.decay + self.hold,
+        }
+    }
+    fn s2f(&self, s: f32) -> usize {
+        (s * self.sample_rate) as usize
+    }
+}
+
+// Which envelope shapes the amplitude of the notes.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum EnvelopeMode {
+    Adsr,
+    Breakpoints,
+}
+
+// Points of a breakpoint envelope, allocated up front so that points can be added on
+// the audio thread.
+pub const MAX_BREAKPOINTS: usize = 16;
+
+// Envelope made of (time in seconds since the trigger, level) points, interpolated
+// with a curve shape, the level staying at the last point. A one-shot ends at the last
+// point, a note held sustains there until note_off, and then releases from the current
+// level, as with ADSR, whose interface it has.
+struct BreakpointEnvelope {
+    // in frames, sorted by time
+    points: Vec<(usize, f32)>,
+    start_time: usize,
+    curve: CurveShape,
+    sample_rate: f32,
+    release: usize,
+    gated: bool,
+    release_time: Option<usize>,
+    release_level: f32,
+    // [0, 1], scales the output
+    velocity: f32,
+}
+
+impl BreakpointEnvelope {
+    // `points` are sorted by time.
+    pub fn new(sample_rate: f32, points: &[(f32, f32)]) -> BreakpointEnvelope {
+        let mut envelope = BreakpointEnvelope {
+            points: Vec::with_capacity(MAX_BREAKPOINTS),
+            start_time: 0,
+            curve: CurveShape::Linear,
+            sample_rate,
+            release: (sample_rate / 100.) as usize,
+            gated: false,
+            // silent until triggered
+            release_time: Some(0),
+            release_level: 0.,
+            velocity: 1.,
+        };
+        for (i, &(time, level)) in points.iter().enumerate() {
+            envelope.set_point(i, time, level);
+        }
+        return envelope;
+    }
+    // Same shape as a one-shot `adsr`.
+    pub fn from_adsr(adsr: &ADSR) -> BreakpointEnvelope {
+        let mut envelope = BreakpointEnvelope::new(adsr.sample_rate, &[]);
+        let attack = adsr.attack;
+        let decay = attack + adsr.decay;
+        let hold = decay + adsr.hold;
+        let release = hold + adsr.release;
+        envelope.points.extend_from_slice(&[
+            (0, 0.),
+            (attack, 1.),
+            (decay, adsr.sustain),
+            (hold, adsr.sustain),
+            (release, 0.),
+        ]);
+        envelope.release = adsr.release;
+        envelope.set_curve(adsr.curve);
+        return envelope;
+    }
+    pub fn render(&mut self, t: usize) -> f32 {
+        return self.velocity * self.level(t);
+    }
+    fn level(&self, t: usize) -> f32 {
+        if t < self.start_time || self.is_finished(t) {
+            return 0.0;
+        }
+        if let Some(release_time) = self.release_time {
+            let x = (t - release_time) as f32 / self.release as f32;
+            return self.release_level - self.release_level * self.curve.apply(x);
+        }
+        let t = t - self.start_time;
+        let mut previous = (0, self.points[0].1);
+        for &(time, level) in self.points.iter() {
+            if t < time {
+                let x = (t - previous.0) as f32 / (time - previous.0) as f32;
+                return previous.1 + (level - previous.1) * self.curve.apply(x);
+            }
+            previous = (time, level);
+        }
+        return previous.1;
+    }
+    // Sets the point `i`, or adds it when `i` is the number of points. Its time stays
+    // between those of its neighbours, so that the points stay sorted.
+    pub fn set_point(&mut self, i: usize, time: f32, level: f32) {
+        if i > self.points.len() || i >= MAX_BREAKPOINTS {
+            return;
+        }
+        let mut frames = (time.max(0.) * self.sample_rate) as usize;
+        if i > 0 {
+            frames = frames.max(self.points[i - 1].0);
+        }
+        if i + 1 < self.points.len() {
+            frames = frames.min(self.points[i + 1].0);
+        }
+        let point = (frames, level.max(0.).min(1.));
+        if i == self.points.len() {
+            self.points.push(point);
+        } else {
+            self.points[i] = point;
+        }
+    }
+    // Keeps the first `count` points. Without any, the envelope is silent.
+    pub fn set_point_count(&mut self, count: usize) {
+        self.points.truncate(count);
+    }
+    pub fn set_release(&mut self, release: f32) {
+        self.release = (release * self.sample_rate) as usize;
+    }
+    pub fn set_curve(&mut self, curve: CurveShape) {
+        self.curve = curve;
+    }
+    // One-shot, from the first point.
+    pub fn trigger_with_velocity(&mut self, time: usize, velocity: f32) {
+        self.start_time = time;
+        self.gated = false;
+        self.release_time = None;
+        self.velocity = velocity.max(0.).min(1.);
+    }
+    // Sustains at the last point until note_off.
+    pub fn note_on(&mut self, time: usize, velocity: f32) {
+        self.trigger_with_velocity(time, velocity);
+        self.gated = true;
+    }
+    // Release from the current level.
+    pub fn note_off(&mut self, time: usize) {
+        if self.release_time.is_some() || self.is_finished(time) {
+            return;
+        }
+        self.release_level = self.level(time);
+        self.release_time = Some(time);
+    }
+    pub fn silence(&mut self) {
+        self.start_time = 0;
+        self.release_time = Some(0);
+        self.release_level = 0.;
+    }
+    pub fn is_finished(&self, t: usize) -> bool {
+        match (self.release_time, self.points.last()) {
+            (Some(time), _) => time.saturating_add(self.release) <= t,
+            (None, None) => true,
+            (None, Some(_)) if self.gated => false,
+            (None, Some(&(time, _))) => self.start_time + time <= t,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Param {
+    v0: f32,
+    v1: f32,
+    counter: isize,
+    smoothing: isize,
+    coeff: f32,
+    time_constant: f32,
+    sample_rate: f32,
+}
+
+impl Param {
+    fn new(sample_rate: f32, v: f32) -> Param {
+        let mut p = Param {
+            v0: v,
+            v1: v,
+            counter: 0,
+            smoothing: 0,
+            coeff: 0.,
+            time_constant: 0.,
+            sample_rate,
+        };
+        p.set_smoothing_time(0.01);
+        p.counter = p.smoothing;
+        return p;
+    }
+    // To be called once per sample: moves v0 exponentially toward v1, and snaps to v1
+    // after `smoothing` samples.
+    fn value(&mut self) -> f32 {
+        if self.counter >= self.smoothing {
+            self.v0 = self.v1;
+            return self.v0;
+        }
+        self.counter += 1;
+        self.v0 = self.v1 + (self.v0 - self.v1) * self.coeff;
+        return self.v0;
+    }
+    // A NaN or an infinity is ignored, it would never be smoothed away.
+    fn set_value(&mut self, v: f32) {
+        if !v.is_finite() {
+            return;
+        }
+        if self.smoothing == 0 {
+            self.set_value_no_smooth(v);
+            return;
+        }
+        self.counter = 0;
+        self.v1 = v;
+    }
+    fn set_value_no_smooth(&mut self, v: f32) {
+        if !v.is_finite() {
+            return;
+        }
+        self.counter = self.smoothing;
+        self.v0 = v;
+        self.v1 = v;
+    }
+    // False once value() has reached the target, until it is set again.
+    fn is_smoothing(&self) -> bool {
+        self.v0 != self.v1
+    }
+    // `seconds` is the time constant, the ramp stops after five time constants, when
+    // the remaining error is below 1%. 0 is instant.
+    fn set_smoothing_time(&mut self, seconds: f32) {
+        let seconds = seconds.max(0.);
+        self.time_constant = seconds;
+        self.coeff = (-1. / (seconds * self.sample_rate)).exp();
+        self.smoothing = (5. * seconds * self.sample_rate) as isize;
+    }
+    // The rate at which value() is called, the smoothing time stays the same.
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        let time_constant = self.time_constant;
+        self.set_smoothing_time(time_constant);
+    }
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub enum Waveform {
+    Sine,
+    Saw,
+    Square,
+    Triangle,
+    WhiteNoise,
+    PinkNoise,
+    // the table loaded with `--wavetable`, a sine if there is none
+    Wavetable,
+}
+
+// A single cycle waveform, read by phase with linear interpolation. Shared by all the
+// oscillators, and loaded before the audio starts.
+#[derive(Clone)]
+pub struct WavetableOsc {
+    table: Arc<Vec<f32>>,
+}
+
+impl WavetableOsc {
+    // A WAV file, or raw little-endian 32-bit floats. All of it is a single cycle,
+    // only the first channel is used.
+    pub fn load_table(path: &str) -> Result<WavetableOsc, Error> {
+        let table: Vec<f32> = if path.ends_with(".wav") {
+            read_wav(path)?.0
+        } else {
+            fs::read(path)
+                .map_err(|e| Error::Io(path.to_string(), e))?
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect()
+        };
+        if table.len() < 2 {
+            return Err(Error::Config(format!("{}: the table needs at least two samples", path)));
+        }
+        Ok(WavetableOsc {
+            table: Arc::new(table),
+        })
+    }
+    // `phase` in [0, 1)
+    fn render(&self, phase: f32) -> f32 {
+        let length = self.table.len();
+        let position = phase * length as f32;
+        let index = position as usize % length;
+        let frac = position - position.floor();
+        let a = self.table[index];
+        let b = self.table[(index + 1) % length];
+        a + (b - a) * frac
+    }
+}
+
+// Size of the sine table, a power of two. With linear interpolation, the error is
+// below 1e-6, under what 24 bits resolve.
+const SINE_TABLE_SIZE: usize = 4096;
+
+// A period of a sine, read by phase with linear interpolation, cheaper than calling
+// sin() per sample. Shared by all the oscillators, and built before the audio starts.
+#[derive(Clone)]
+pub struct SineTable {
+    // one more point than the size, so that interpolating never wraps
+    table: Arc<Vec<f32>>,
+}
+
+impl SineTable {
+    pub fn new() -> SineTable {
+        let table = (0..=SINE_TABLE_SIZE)
+            .map(|i| (2. * PI * i as f32 / SINE_TABLE_SIZE as f32).sin())
+            .collect();
+        SineTable {
+            table: Arc::new(table),
+        }
+    }
+    // `phase` in [0, 1]
+    fn render(&self, phase: f32) -> f32 {
+        let position = phase * SINE_TABLE_SIZE as f32;
+        let index = position as usize;
+        let frac = position - index as f32;
+        let index = index & (SINE_TABLE_SIZE - 1);
+        let a = self.table[index];
+        let b = self.table[index + 1];
+        a + (b - a) * frac
+    }
+}
+
+// The first channel of a WAV file, and its sample rate.
+fn read_wav(path: &str) -> Result<(Vec<f32>, u32), Error> {
+    let error = |e| Error::Wav(path.to_string(), e);
+    let mut reader = hound::WavReader::open(path).map_err(error)?;
+    let spec = reader.spec();
+    let channels = spec.channels as usize;
+    let samples: Result<Vec<f32>, _> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect(),
+        hound::SampleFormat::Int => {
+            let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|s| s.map(|s| s as f32 / scale))
+                .collect()
+        }
+    };
+    let samples = samples
+        .map_err(error)?
+        .into_iter()
+        .step_by(channels.max(1))
+        .collect();
+    Ok((samples, spec.sample_rate))
+}
+
+// A short sound, e.g. a drum hit, loaded from a WAV file before the audio starts. Only
+// the first channel is used.
+#[derive(Clone)]
+pub struct Sample {
+    data: Arc<Vec<f32>>,
+    sample_rate: f32,
+}
+
+impl Sample {
+    pub fn load(path: &str) -> Result<Sample, Error> {
+        let (data, sample_rate) = read_wav(path)?;
+        if data.len() < 2 {
+            return Err(Error::Config(format!("{}: the sample needs at least two samples", path)));
+        }
+        Ok(Sample {
+            data: Arc::new(data),
+            sample_rate: sample_rate as f32,
+        })
+    }
+}
+
+// seconds, of the fade in and out of the samples, so that they don't click when cut
+const SAMPLE_FADE: f32 = 0.001;
+
+// Plays a Sample once from its start, through an envelope that lasts as long as it.
+// Triggering it again while it plays restarts it.
+struct SampleVoice {
+    sample: Sample,
+    env: ADSR,
+    // in frames of the sample
+    position: f32,
+    // frames of the sample per frame of output
+    rate: f32,
+    sample_rate: f32,
+    active: bool,
+}
+
+impl SampleVoice {
+    fn new(sample: Sample, sample_rate: f32) -> SampleVoice {
+        let mut env = ADSR::new(sample_rate);
+        env.set_attack(SAMPLE_FADE);
+        env.set_release(SAMPLE_FADE);
+        let mut voice = SampleVoice {
+            sample,
+            env,
+            position: 0.,
+            rate: 1.,
+            sample_rate,
+            active: false,
+        };
+        voice.set_pitch(0.);
+        voice
+    }
+    fn trigger(&mut self, time: usize, velocity: f32) {
+        let duration = (self.sample.data.len() - 1) as f32 / self.rate / self.sample_rate;
+        self.env.set_hold((duration - 2. * SAMPLE_FADE).max(0.));
+        self.env.trigger_with_velocity(time, velocity);
+        self.position = 0.;
+        self.active = true;
+    }
+    fn render(&mut self, t: usize) -> f32 {
+        if !self.active {
+            return 0.;
+        }
+        let data = &self.sample.data;
+        let index = self.position as usize;
+        if index + 1 >= data.len() || self.env.is_finished(t) {
+            self.active = false;
+            return 0.;
+        }
+        let frac = self.position - index as f32;
+        let x = data[index] + (data[index + 1] - data[index]) * frac;
+        self.position += self.rate;
+        x * self.env.render(t)
+    }
+    // Semitones, from the next trigger. Higher is faster, and shorter.
+    fn set_pitch(&mut self, semitones: f32) {
+        self.rate = (semitones / 12.).exp2() * self.sample.sample_rate / self.sample_rate;
+    }
+}
+
+// Seed of the noise of each oscillator, so that renders are reproducible.
+pub const NOISE_SEED: u32 = 0x9e37_79b9;
+
+// White noise from a xorshift generator, and pink noise obtained by filtering it
+// (Paul Kellet's economy filter, -3dB/octave above ~10Hz). No allocation, safe to
+// use on the audio thread.
+pub struct Noise {
+    state: u32,
+    b0: f32,
+    b1: f32,
+    b2: f32,
+}
+
+impl Noise {
+    pub fn new(seed: u32) -> Noise {
+        Noise {
+            // xorshift never leaves 0
+            state: if seed == 0 { NOISE_SEED } else { seed },
+            b0: 0.,
+            b1: 0.,
+            b2: 0.,
+        }
+    }
+    // [-1, 1]
+    pub fn white(&mut self) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state as f32 / u32::MAX as f32 * 2. - 1.
+    }
+    fn pink(&mut self) -> f32 {
+        let white = self.white();
+        self.b0 = 0.99765 * self.b0 + white * 0.0990460;
+        self.b1 = 0.96300 * self.b1 + white * 0.2965164;
+        self.b2 = 0.57000 * self.b2 + white * 1.0526913;
+        // roughly back to [-1, 1]
+        (self.b0 + self.b1 + self.b2 + white * 0.1848) * 0.25
+    }
+}
+
+// Polynomial correction for a unit step at t = 0, t being the phase in [0, 1) and dt
+// the phase increment per sample.
+fn polyblep(t: f32, dt: f32) -> f32 {
+    if t < dt {
+        let t = t / dt;
+        return t + t - t * t - 1.;
+    }
+    if t > 1. - dt {
+        let t = (t - 1.) / dt;
+        return t * t + t + t + 1.;
+    }
+    return 0.;
+}
+
+struct Oscillator {
+    phase: f32,
+    sample_rate: f32,
+    frequency: Param,
+    detune: Param,
+    // of the frequency, from `detune`, only computed when it moves
+    detune_ratio: f32,
+    waveform: Waveform,
+    // amount of the previous output that modulates the frequency, [0, 1]
+    feedback: Param,
+    last: f32,
+    noise: Noise,
+    wavetable: Option<WavetableOsc>,
+    // used for Waveform::Sine when set
+    sine_table: Option<SineTable>,
+}
+
+impl Oscillator {
+    fn new(sample_rate: f32) -> Oscillator  {
+        Oscillator {
+            phase: 0.,
+            frequency: Param::new(sample_rate, 440.),
+            sample_rate,
+            detune: Param::new(sample_rate, 0.),
+            detune_ratio: 1.,
+            waveform: Waveform::Sine,
+            feedback: Param::new(sample_rate, 0.),
+            last: 0.,
+            noise: Noise::new(NOISE_SEED),
+            wavetable: None,
+            sine_table: None,
+        }
+    }
+    fn render(&mut self, t: usize) -> f32 {
+        self.render_with_offset(t, 0.)
+    }
+    // `offset` is added to the phase, in radians, for phase modulation.
+    fn render_with_offset(&mut self, _t: usize, offset: f32) -> f32 {
+        if self.detune.is_smoothing() {
+            self.detune_ratio = (self.detune.value() / 1200.).exp2();
+        }
+        let final_frequency = self.frequency.value() * self.detune_ratio;
+        let phase_increment =
+            2. * PI * final_frequency / self.sample_rate * (1. + self.feedback.value() * self.last);
+        let phase = if offset == 0. {
+            self.phase
+        } else {
+            (self.phase + offset).rem_euclid(2. * PI)
+        };
+        // phase and phase increment normalized to [0, 1), for the polyblep
+        let p = phase / (2. * PI);
+        let dt = (phase_increment / (2. * PI)).abs();
+        // naive shapes, computed from the phase in [0, 2 * PI), the discontinuities of
+        // the saw and square are smoothed with a polyblep
+        let rv = match self.waveform {
+            Waveform::Sine => match self.sine_table {
+                Some(ref table) => table.render(p),
+                None => phase.sin(),
+            },
+            Waveform::Saw => phase / PI - 1. - polyblep(p, dt),
+            Waveform::Square => {
+                let naive = if phase < PI { 1. } else { -1. };
+                naive + polyblep(p, dt) - polyblep((p + 0.5) % 1., dt)
+            }
+            Waveform::Triangle => 2. * (phase - PI).abs() / PI - 1.,
+            // the phase still runs, so that switching back is continuous
+            Waveform::WhiteNoise => self.noise.white(),
+            Waveform::PinkNoise => self.noise.pink(),
+            Waveform::Wavetable => match self.wavetable {
+                Some(ref wavetable) => wavetable.render(p),
+                None => phase.sin(),
+            },
+        };
+        self.phase += phase_increment;
+
+        if self.phase > 2. * PI {
+            self.phase -= 2. * PI;
+        }
+        // deep modulation can make the instantaneous frequency negative
+        if self.phase < 0. {
+            self.phase += 2. * PI;
+        }
+        if !self.phase.is_finite() {
+            self.phase = 0.;
+        }
+        self.last = rv;
+        return rv;
+    }
+    // [0, sample_rate / 2]
+    fn set_frequency(&mut self, frequency: f32) {
+        let nyquist = self.sample_rate / 2.;
+        self.frequency.set_value(frequency.max(0.).min(nyquist));
+    }
+    // Called per sample by the FM, where deep modulation can make the instantaneous
+    // frequency negative: only its magnitude is clamped, to the Nyquist frequency.
+    fn set_frequency_no_smooth(&mut self, frequency: f32) {
+        let nyquist = self.sample_rate / 2.;
+        self.frequency.set_value_no_smooth(frequency.max(-nyquist).min(nyquist));
+    }
+    // [-1200, 1200]
+    fn set_detune(&mut self, detune: f32) {
+        self.detune.set_value(detune.max(-1200.).min(1200.));
+        if !self.detune.is_smoothing() {
+            self.detune_ratio = (self.detune.value() / 1200.).exp2();
+        }
+    }
+    // The phase is left untouched, so that switching is continuous.
+    fn set_waveform(&mut self, waveform: Waveform) {
+        self.waveform = waveform;
+    }
+    fn set_feedback(&mut self, feedback: f32) {
+        self.feedback.set_value(feedback.max(0.).min(1.));
+    }
+    fn set_wavetable(&mut self, wavetable: WavetableOsc) {
+        self.wavetable = Some(wavetable);
+    }
+    fn set_sine_table(&mut self, table: Option<SineTable>) {
+        self.sine_table = table;
+    }
+    fn set_phase(&mut self, phase: f32) {
+        // modulo 2 * PI ?
+        self.phase = phase;
+    }
+    // Takes the phase and the state of `other`, so that both render the same from there.
+    fn follow(&mut self, other: &Oscillator) {
+        self.phase = other.phase;
+        self.last = other.last;
+        self.detune = other.detune.clone();
+        self.detune_ratio = other.detune_ratio;
+        self.feedback = other.feedback.clone();
+    }
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        self.frequency.set_sample_rate(sample_rate);
+        self.detune.set_sample_rate(sample_rate);
+        self.feedback.set_sample_rate(sample_rate);
+    }
+}
+
+const OPERATORS: usize = 3;
+
+// Which operator modulates which: an operator is only ever modulated by operators
+// with a higher index, so rendering from the last operator to the first one is a
+// topological order.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Algorithm {
+    // 2 -> 1 -> 0
+    Stack,
+    // 1 -> 0 <- 2
+    Parallel,
+}
+
+impl Algorithm {
+    // routing()[i][j] is true when operator j modulates operator i
+    fn routing(self) -> [[bool; OPERATORS]; OPERATORS] {
+        match self {
+            Algorithm::Stack => [
+                [false, true, false],
+                [false, false, true],
+                [false, false, false],
+            ],
+            Algorithm::Parallel => [
+                [false, true, true],
+                [false, false, false],
+                [false, false, false],
+            ],
+        }
+    }
+    // The algorithm the morph fades to, from this one.
+    fn morph_target(self) -> Algorithm {
+        match self {
+            Algorithm::Stack => Algorithm::Parallel,
+            Algorithm::Parallel => Algorithm::Stack,
+        }
+    }
+}
+
+// An oscillator with its own envelope and level. The level is the output volume of
+// a carrier, or the modulation index of a modulator. It has an oscillator for the
+// algorithm and another one for its morph target, so that both can be rendered.
+struct Operator {
+    oscs: [Oscillator; 2],
+    env: ADSR,
+    level: Param,
+    // frequency relative to the carrier frequency, or absolute when `fixed`
+    ratio: f32,
+    fixed: bool,
+    frequency: Param,
+}
+
+impl Operator {
+    fn new(sample_rate: f32, ratio: f32, level: f32) -> Operator {
+        let mut env = ADSR::new(sample_rate);
+        env.note_on(0, 1.);
+        Operator {
+            oscs: [Oscillator::new(sample_rate), Oscillator::new(sample_rate)],
+            env,
+            level: Param::new(sample_rate, level),
+            ratio,
+            fixed: false,
+            frequency: Param::new(sample_rate, 440.),
+        }
+    }
+    // The envelope runs at the base rate, it is rendered from the frame time.
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        for osc in self.oscs.iter_mut() {
+            osc.set_sample_rate(sample_rate);
+        }
+        self.level.set_sample_rate(sample_rate);
+        self.frequency.set_sample_rate(sample_rate);
+    }
+}
+
+// How a modulator acts on the operator it modulates: by offsetting its frequency,
+// proportionally to it, or by offsetting its phase, in radians, like the DX7.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum FmMode {
+    Frequency,
+    Phase,
+}
+
+// FM voice made of OPERATORS operators: a modulator offsets the frequency of the
+// operator it modulates, proportionally to that frequency, so that the depth tracks
+// the pitch. Operator 0 is the carrier, it follows the note frequency. The others
+// either have a fixed frequency, or follow the carrier with a ratio, whichever has
+// been set last.
+struct FmVoice {
+    operators: Vec<Operator>,
+    algorithm: Algorithm,
+    // [0, 1], from `algorithm` to its morph target
+    morph: Param,
+    carrier_freq: Param,
+    // multiplies the modulation of all the operators, set from the velocity
+    modulation_scale: f32,
+    fm_mode: FmMode,
+    // of the carrier, at the last sample rendered, pitch modulation included
+    frequency: f32,
+    // multiplies the frequency of the operators other than the first one, to detune the
+    // sides of a stereo voice
+    modulator_detune: f32,
+    // contour of the modulation over the note, separate from the amplitude envelope
+    mod_env: ADSR,
+    // [0, 1], at 0 the modulation is constant, at 1 it follows `mod_env` entirely
+    mod_env_amount: f32,
+}
+
+impl FmVoice {
+    fn new(sample_rate: f32) -> FmVoice {
+        let mut operators = vec![
+            Operator::new(sample_rate, 1., 1.),
+            Operator::new(sample_rate, 1., 1.),
+            Operator::new(sample_rate, 2., 0.),
+        ];
+        operators[1].fixed = true;
+        FmVoice {
+            operators,
+            algorithm: Algorithm::Stack,
+            morph: Param::new(sample_rate, 0.),
+            carrier_freq: Param::new(sample_rate, 440.),
+            modulation_scale: 1.,
+            fm_mode: FmMode::Frequency,
+            frequency: 440.,
+            modulator_detune: 1.,
+            mod_env: ADSR::new(sample_rate),
+            mod_env_amount: 0.,
+        }
+    }
+    // `pitch_mod` multiplies the carrier frequency, e.g. for vibrato.
+    fn render(&mut self, t: usize, pitch_mod: f32) -> f32 {
+        self.render_modulated(t, pitch_mod, 0.)
+    }
+    // `input` is added to the modulation of the first operator, e.g. from another voice.
+    fn render_modulated(&mut self, t: usize, pitch_mod: f32, input: f32) -> f32 {
+        let carrier_freq = self.carrier_freq.value() * pitch_mod;
+        self.frequency = carrier_freq;
+        let contour = 1. - self.mod_env_amount * (1. - self.mod_env.render(t));
+        let scale = self.modulation_scale * contour;
+        // the same for both routings: the gain of each operator, that of the modulators
+        // scaling the modulation they give, and the frequencies
+        let mut gains = [0.; OPERATORS];
+        let mut bases = [0.; OPERATORS];
+        for (i, op) in self.operators.iter_mut().enumerate() {
+            gains[i] = op.env.render(t) * op.level.value();
+            bases[i] = if op.fixed {
+                op.frequency.value()
+            } else {
+                carrier_freq * op.ratio
+            };
+            if i != 0 {
+                gains[i] *= scale;
+                bases[i] *= self.modulator_detune;
+            }
+        }
+        // Both routings are rendered, and their outputs crossfaded. At either end, only
+        // one is rendered, and the oscillators of the other follow it, so that both
+        // start in phase when the morph moves.
+        let morph = self.morph.value();
+        let routings = [self.algorithm.routing(), self.algorithm.morph_target().routing()];
+        if morph == 0. || morph == 1. {
+            let path = morph as usize;
+            let out = self.render_routing(t, path, &routings[path], &gains, &bases, input);
+            for op in self.operators.iter_mut() {
+                let [from, to] = &mut op.oscs;
+                if path == 0 {
+                    to.follow(from);
+                } else {
+                    from.follow(to);
+                }
+            }
+            return out;
+        }
+        let from = self.render_routing(t, 0, &routings[0], &gains, &bases, input);
+        let to = self.render_routing(t, 1, &routings[1], &gains, &bases, input);
+        return from + (to - from) * morph;
+    }
+    // The output of the carrier, with the oscillators `path` of the operators wired as
+    // `routing`.
+    fn render_routing(
+        &mut self,
+        t: usize,
+        path: usize,
+        routing: &[[bool; OPERATORS]; OPERATORS],
+        gains: &[f32; OPERATORS],
+        bases: &[f32; OPERATORS],
+        input: f32,
+    ) -> f32 {
+        let mut out = [0.; OPERATORS];
+        for i in (0..OPERATORS).rev() {
+            let mut m = 0.;
+            for j in (i + 1)..OPERATORS {
+                if routing[i][j] {
+                    m += out[j];
+                }
+            }
+            if i == 0 {
+                m += input;
+            }
+            let base = bases[i];
+            let osc = &mut self.operators[i].oscs[path];
+            let x = match self.fm_mode {
+                FmMode::Frequency => {
+                    osc.set_frequency_no_smooth(base + m * base);
+                    osc.render(t)
+                }
+                FmMode::Phase => {
+                    osc.set_frequency_no_smooth(base);
+                    osc.render_with_offset(t, m)
+                }
+            };
+            out[i] = x * gains[i];
+        }
+        return out[0];
+    }
+    // Starts the operator envelopes, and the modulation envelope.
+    fn note_on(&mut self, time: usize) {
+        for op in self.operators.iter_mut() {
+            op.env.note_on(time, 1.);
+        }
+        self.mod_env.note_on(time, 1.);
+    }
+    fn note_off(&mut self, time: usize) {
+        for op in self.operators.iter_mut() {
+            op.env.note_off(time);
+        }
+        self.mod_env.note_off(time);
+    }
+    fn set_gate(&mut self, gate: bool, time: usize) {
+        for op in self.operators.iter_mut() {
+            op.env.set_gate(gate, time);
+        }
+        self.mod_env.set_gate(gate, time);
+    }
+    fn set_carrier_frequency(&mut self, frequency: f32) {
+        self.carrier_freq.set_value(frequency);
+    }
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        for op in self.operators.iter_mut() {
+            op.set_sample_rate(sample_rate);
+        }
+        self.morph.set_sample_rate(sample_rate);
+        self.carrier_freq.set_sample_rate(sample_rate);
+    }
+    // Starts the next carrier frequency change from `frequency`.
+    fn glide_from(&mut self, frequency: f32) {
+        self.carrier_freq.set_value_no_smooth(frequency);
+    }
+    // Time for the carrier to reach a new frequency, exponentially. 0 is only
+    // declicking.
+    fn set_glide(&mut self, seconds: f32) {
+        let time_constant = if seconds > 0. { seconds / 5. } else { 0.01 };
+        self.carrier_freq.set_smoothing_time(time_constant);
+    }
+    fn set_algorithm(&mut self, algorithm: Algorithm) {
+        self.algorithm = algorithm;
+    }
+    // [0, 1], 0 is `algorithm`, 1 its morph target.
+    fn set_algorithm_morph(&mut self, morph: f32) {
+        self.morph.set_value(morph.max(0.).min(1.));
+    }
+    fn set_modulation_scale(&mut self, scale: f32) {
+        self.modulation_scale = scale;
+    }
+    fn set_modulator_detune(&mut self, ratio: f32) {
+        self.modulator_detune = ratio;
+    }
+    fn set_mod_env_amount(&mut self, amount: f32) {
+        self.mod_env_amount = amount.max(0.).min(1.);
+    }
+    fn set_fm_mode(&mut self, mode: FmMode) {
+        self.fm_mode = mode;
+    }
+    fn set_operator_frequency(&mut self, operator: usize, frequency: f32) {
+        if let Some(op) = self.operators.get_mut(operator) {
+            op.frequency.set_value(frequency);
+            op.fixed = operator != 0;
+        }
+    }
+    fn set_operator_ratio(&mut self, operator: usize, ratio: f32) {
+        if let Some(op) = self.operators.get_mut(operator) {
+            op.ratio = ratio;
+            op.fixed = false;
+        }
+    }
+    fn set_operator_level(&mut self, operator: usize, level: f32) {
+        if let Some(op) = self.operators.get_mut(operator) {
+            op.level.set_value(level);
+        }
+    }
+    fn set_modulator_frequency(&mut self, frequency: f32) {
+        self.set_operator_frequency(1, frequency);
+    }
+    fn set_ratio(&mut self, ratio: f32) {
+        self.set_operator_ratio(1, ratio);
+    }
+    fn set_mod_index(&mut self, mod_index: f32) {
+        self.set_operator_level(1, mod_index);
+    }
+    fn set_feedback(&mut self, feedback: f32) {
+        for osc in self.operators[1].oscs.iter_mut() {
+            osc.set_feedback(feedback);
+        }
+    }
+    fn set_waveform(&mut self, waveform: Waveform) {
+        for osc in self.operators[0].oscs.iter_mut() {
+            osc.set_waveform(waveform);
+        }
+    }
+    fn set_wavetable(&mut self, wavetable: &WavetableOsc) {
+        for osc in self.operators.iter_mut().flat_map(|op| op.oscs.iter_mut()) {
+            osc.set_wavetable(wavetable.clone());
+        }
+    }
+    fn set_sine_table(&mut self, table: Option<&SineTable>) {
+        for osc in self.operators.iter_mut().flat_map(|op| op.oscs.iter_mut()) {
+            osc.set_sine_table(table.cloned());
+        }
+    }
+    // Detunes all the operators, so that the timbre doesn't change.
+    fn set_detune(&mut self, detune: f32) {
+        for osc in self.operators.iter_mut().flat_map(|op| op.oscs.iter_mut()) {
+            osc.set_detune(detune);
+        }
+    }
+    // The same phase for the oscillators of both routings.
+    fn set_operator_phase(&mut self, operator: usize, phase: f32) {
+        for osc in self.operators[operator].oscs.iter_mut() {
+            osc.set_phase(phase);
+        }
+    }
+}
+
+// Recursive state decaying towards zero ends up in denormal floats, that are very
+// slow to compute with on most CPUs. Flush anything below -300dB to zero. This is
+// applied to the state of the filter, and to what is fed back into the delay line.
+// A NaN or an infinity would stay there forever, they are flushed too.
+fn flush_denormal(x: f32) -> f32 {
+    if x.abs() < 1e-15 || !x.is_finite() {
+        0.
+    } else {
+        x
+    }
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub enum FilterType {
+    LowPass,
+    HighPass,
+    BandPass,
+}
+
+// Trapezoidal state-variable filter (Simper). The bilinear prewarping keeps it
+// stable up to Nyquist, whatever the resonance. All the outputs are computed from
+// the same state, so that changing the type is continuous.
+struct Filter {
+    filter_type: FilterType,
+    ic1eq: f32,
+    ic2eq: f32,
+    cutoff: f32,
+    resonance: f32,
+    g: f32,
+    k: f32,
+    sample_rate: f32,
+}
+
+impl Filter {
+    fn new(sample_rate: f32) -> Filter {
+        let mut filter = Filter {
+            filter_type: FilterType::LowPass,
+            ic1eq: 0.,
+            ic2eq: 0.,
+            cutoff: 20000.,
+            resonance: 0.,
+            g: 0.,
+            k: 0.,
+            sample_rate,
+        };
+        filter.update_coefficients();
+        return filter;
+    }
+    fn process(&mut self, sample: f32) -> f32 {
+        let a1 = 1. / (1. + self.g * (self.g + self.k));
+        let a2 = self.g * a1;
+        let a3 = self.g * a2;
+        let v3 = sample - self.ic2eq;
+        let v1 = a1 * self.ic1eq + a2 * v3;
+        let v2 = self.ic2eq + a2 * self.ic1eq + a3 * v3;
+        self.ic1eq = flush_denormal(2. * v1 - self.ic1eq);
+        self.ic2eq = flush_denormal(2. * v2 - self.ic2eq);
+        return match self.filter_type {
+            FilterType::LowPass => v2,
+            FilterType::HighPass => sample - self.k * v1 - v2,
+            FilterType::BandPass => v1,
+        };
+    }
+    fn set_type(&mut self, filter_type: FilterType) {
+        self.filter_type = filter_type;
+    }
+    // Hz, clamped below Nyquist
+    fn set_cutoff(&mut self, cutoff: f32) {
+        self.cutoff = cutoff;
+        self.update_coefficients();
+    }
+    // [0, 1], 1 being close to self-oscillation
+    fn set_resonance(&mut self, resonance: f32) {
+        self.resonance = resonance;
+        self.update_coefficients();
+    }
+    fn update_coefficients(&mut self) {
+        let cutoff = self.cutoff.max(10.).min(self.sample_rate * 0.49);
+        self.g = (PI * cutoff / self.sample_rate).tan();
+        self.k = 2. * (1. - self.resonance.max(0.).min(0.99));
+    }
+}
+
+pub const MAX_UNISON: usize = 7;
+
+// Equal-power pan law, `pan` in [-1, 1], -1 being left.
+fn pan_gains(pan: f32) -> (f32, f32) {
+    let angle = (pan.max(-1.).min(1.) + 1.) * PI / 4.;
+    (angle.cos(), angle.sin())
+}
+
+// How the velocity of the notes played responds to touch, [0,1] -> [0,1].
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum VelCurve {
+    Linear,
+    // loud notes are easier to reach
+    Soft,
+    // soft notes are easier to reach
+    Hard,
+}
+
+impl VelCurve {
+    pub fn apply(self, velocity: f32) -> f32 {
+        let velocity = velocity.max(0.).min(1.);
+        match self {
+            VelCurve::Linear => velocity,
+            VelCurve::Soft => velocity.sqrt(),
+            VelCurve::Hard => velocity * velocity,
+        }
+    }
+}
+
+// At full humanize, the most a note is detuned in cents, its attack time is
+// lengthened or shortened, and its level lowered.
+const HUMANIZE_CENTS: f32 = 10.;
+const HUMANIZE_ATTACK: f32 = 0.5;
+const HUMANIZE_LEVEL: f32 = 0.25;
+
+// Time a stolen voice takes to fade out, before playing its new note, in seconds.
+const STEAL_FADE: f32 = 0.005;
+
+#[derive(Debug, Copy, Clone)]
+struct Note {
+    frequency: f32,
+    // the pitch slides from there when set
+    glide_from: Option<f32>,
+    pan: f32,
+    // held until note_off, or a one-shot when None
+    velocity: Option<f32>,
+    // humanized: cents, and factors of the attack time and of the level
+    detune: f32,
+    attack_scale: f32,
+    level: f32,
+    // MIDI, counting from 0, and the bend of that channel, in semitones
+    channel: u8,
+    bend: f32,
+    envelope: EnvelopeMode,
+}
+
+// At full stereo spread, how much the modulators of each side of a stereo voice are
+// detuned, up on the right and down on the left, as a ratio.
+const STEREO_FM_DETUNE: f32 = 0.01;
+// Modulation index of each side of a stereo voice by the other, at full cross-modulation.
+const CROSS_MOD_INDEX: f32 = 2.;
+
+struct Voice {
+    // unison copies, the first one is centered and not detuned. There are 2 *
+    // MAX_UNISON: in stereo, the first MAX_UNISON are the left side of each copy, and
+    // the others their right side.
+    fm: Vec<FmVoice>,
+    stereo: bool,
+    // [0, 1], how much each side modulates the other one
+    cross_mod: f32,
+    // the output of the left and right sides of each copy, at the previous frame
+    sides: [(f32, f32); MAX_UNISON],
+    gains: [(f32, f32); MAX_UNISON],
+    // stereo position of each copy, relative to `pan`
+    positions: [f32; MAX_UNISON],
+    // [-1, 1], -1 being left
+    pan: f32,
+    // the detuning of the copies is `positions` times this
+    spread_cents: f32,
+    // semitones, of the channel of the note playing
+    bend: f32,
+    channel: u8,
+    // cents, of the note playing, when humanized
+    note_detune: f32,
+    unison: usize,
+    env: ADSR,
+    // played instead of `env` in EnvelopeMode::Breakpoints, set with each note
+    breakpoints: BreakpointEnvelope,
+    envelope: EnvelopeMode,
+    frequency: f32,
+    start_time: usize,
+    active: bool,
+    // when stolen, the next note, and when it starts, no note when silenced
+    pending: Option<(Option<Note>, usize)>,
+    steal_fade: usize,
+    // phase of the oscillators at the start of a note, free-running when None
+    retrigger_phase: Option<f32>,
+    // [0, 1], how much the velocity scales the modulation index
+    vel_to_mod: f32,
+    // an octave below the carrier, mixed in at `sub_level`
+    sub_osc: Oscillator,
+    sub_level: Param,
+    sub_gains: (f32, f32),
+}
+
+impl Voice {
+    fn new(sample_rate: f32) -> Voice {
+        let mut v = Voice {
+            fm: (0..2 * MAX_UNISON).map(|_| FmVoice::new(sample_rate)).collect(),
+            stereo: false,
+            cross_mod: 0.,
+            sides: [(0., 0.); MAX_UNISON],
+            gains: [(0., 0.); MAX_UNISON],
+            positions: [0.; MAX_UNISON],
+            pan: 0.,
+            spread_cents: 0.,
+            bend: 0.,
+            channel: 0,
+            note_detune: 0.,
+            unison: 1,
+            env: ADSR::new(sample_rate),
+            breakpoints: BreakpointEnvelope::from_adsr(&ADSR::new(sample_rate)),
+            envelope: EnvelopeMode::Adsr,
+            frequency: 0.,
+            start_time: 0,
+            active: false,
+            pending: None,
+            steal_fade: (STEAL_FADE * sample_rate) as usize,
+            retrigger_phase: None,
+            vel_to_mod: 0.,
+            sub_osc: Oscillator::new(sample_rate),
+            sub_level: Param::new(sample_rate, 0.),
+            sub_gains: pan_gains(0.),
+        };
+        // spread the initial phases so that the copies don't add up in phase
+        for (i, fm) in v.fm.iter_mut().enumerate() {
+            let i = i % MAX_UNISON;
+            fm.set_operator_phase(0, 2. * PI * i as f32 / MAX_UNISON as f32);
+        }
+        v.set_unison(1, 0.);
+        return v;
+    }
+    // The copies keep phases spread apart, so that they don't add up in phase.
+    fn reset_phases(&mut self, phase: f32) {
+        for (i, fm) in self.fm.iter_mut().enumerate() {
+            for operator in 0..OPERATORS {
+                fm.set_operator_phase(operator, phase);
+            }
+            let spread = 2. * PI * (i % MAX_UNISON) as f32 / MAX_UNISON as f32;
+            fm.set_operator_phase(0, (phase + spread) % (2. * PI));
+        }
+    }
+    fn render(&mut self, t: usize, pitch_mod: f32) -> (f32, f32) {
+        let mut fade = 1.;
+        if let Some((note, start)) = self.pending {
+            if t >= start {
+                // faded out already
+                self.env.silence();
+                self.breakpoints.silence();
+                match note {
+                    Some(note) => self.play(t, note),
+                    None => {
+                        self.pending = None;
+                        self.active = false;
+                    }
+                }
+            } else {
+                fade = (start - t) as f32 / self.steal_fade as f32;
+            }
+        }
+        let (finished, level) = match self.envelope {
+            EnvelopeMode::Adsr => (self.env.is_finished(t), self.env.render(t)),
+            EnvelopeMode::Breakpoints => {
+                (self.breakpoints.is_finished(t), self.breakpoints.render(t))
+            }
+        };
+        if finished {
+            self.active = false;
+        }
+        if !self.active {
+            return (0., 0.);
+        }
+        let g = fade * level;
+        let (mut l, mut r) = (0., 0.);
+        for i in 0..self.unison {
+            if self.stereo {
+                let (last_left, last_right) = self.sides[i];
+                let index = CROSS_MOD_INDEX * self.cross_mod;
+                let left = self.fm[i].render_modulated(t, pitch_mod, index * last_right);
+                let right =
+                    self.fm[MAX_UNISON + i].render_modulated(t, pitch_mod, index * last_left);
+                self.sides[i] = (left, right);
+                l += g * left * self.gains[i].0;
+                r += g * right * self.gains[i].1;
+            } else {
+                let s = g * self.fm[i].render(t, pitch_mod);
+                l += s * self.gains[i].0;
+                r += s * self.gains[i].1;
+            }
+        }
+        let sub_level = self.sub_level.value();
+        if sub_level > 0. {
+            self.sub_osc.set_frequency_no_smooth(self.fm[0].frequency / 2.);
+            let s = g * sub_level * self.sub_osc.render(t);
+            l += s * self.sub_gains.0;
+            r += s * self.sub_gains.1;
+        }
+        return (l, r);
+    }
+    fn play(&mut self, time: usize, note: Note) {
+        self.pending = None;
+        self.frequency = note.frequency;
+        self.start_time = time;
+        self.active = true;
+        self.set_pan(note.pan);
+        if let Some(phase) = self.retrigger_phase {
+            self.reset_phases(phase);
+        }
+        // one-shot notes are at full velocity
+        let velocity = note.velocity.unwrap_or(1.);
+        let modulation_scale = 1. - self.vel_to_mod * (1. - velocity);
+        for fm in self.fm.iter_mut() {
+            fm.set_modulation_scale(modulation_scale);
+            if let Some(from) = note.glide_from {
+                fm.glide_from(from);
+            }
+            fm.set_carrier_frequency(note.frequency);
+            fm.note_on(time);
+        }
+        self.env.set_attack_scale(note.attack_scale);
+        match note.velocity {
+            Some(velocity) => {
+                self.env.note_on(time, velocity * note.level);
+                self.breakpoints.note_on(time, velocity * note.level);
+            }
+            None => {
+                self.env.trigger_with_velocity(time, note.level);
+                self.breakpoints.trigger_with_velocity(time, note.level);
+            }
+        }
+        self.envelope = note.envelope;
+        self.note_detune = note.detune;
+        self.channel = note.channel;
+        self.bend = note.bend;
+        self.update_detune();
+    }
+    // Fades out what is playing, and then plays `note`, to avoid a click.
+    fn steal(&mut self, time: usize, note: Note) {
+        self.frequency = note.frequency;
+        self.start_time = time;
+        self.pending = Some((Some(note), time + self.steal_fade));
+    }
+    // Fades out what is playing, quickly but without a click.
+    fn panic(&mut self, time: usize) {
+        if self.active {
+            self.pending = Some((None, time + self.steal_fade));
+        } else {
+            self.pending = None;
+        }
+    }
+    fn set_gate(&mut self, gate: bool, time: usize) {
+        self.env.set_gate(gate, time);
+        for fm in self.fm.iter_mut() {
+            fm.set_gate(gate, time);
+        }
+    }
+    // Current output level of the envelope.
+    fn level(&self, t: usize) -> f32 {
+        if !self.active {
+            return 0.;
+        }
+        match self.envelope {
+            EnvelopeMode::Adsr => self.env.velocity * self.env.level(t),
+            EnvelopeMode::Breakpoints => self.breakpoints.velocity * self.breakpoints.level(t),
+        }
+    }
+    fn stop(&mut self, time: usize) {
+        // released before it could start, a voice being silenced stays silenced
+        if let Some((Some(_), _)) = self.pending {
+            self.pending = None;
+        }
+        self.env.note_off(time);
+        self.breakpoints.note_off(time);
+        for fm in self.fm.iter_mut() {
+            fm.note_off(time);
+        }
+    }
+    // Copies are detuned symmetrically around the center one, within `spread_cents`,
+    // and panned according to their detuning.
+    fn set_unison(&mut self, voices: usize, spread_cents: f32) {
+        self.unison = voices.max(1).min(MAX_UNISON);
+        let pairs = (self.unison / 2).max(1) as f32;
+        for i in 0..self.unison {
+            let side = if i % 2 == 1 { 1. } else { -1. };
+            self.positions[i] = side * ((i + 1) / 2) as f32 / pairs;
+        }
+        self.spread_cents = spread_cents;
+        self.update_detune();
+        self.update_gains();
+    }
+    // The bend is applied with the detune of the oscillators, that is smoothed.
+    fn set_bend(&mut self, semitones: f32) {
+        self.bend = semitones;
+        self.update_detune();
+    }
+    fn update_detune(&mut self) {
+        let detune = self.bend * 100. + self.note_detune;
+        for i in 0..self.unison {
+            let cents = self.positions[i] * self.spread_cents + detune;
+            self.fm[i].set_detune(cents);
+            self.fm[MAX_UNISON + i].set_detune(cents);
+        }
+        self.sub_osc.set_detune(detune);
+    }
+    // The unison copies are spread around `pan`, and pushed to the side when they
+    // go past it.
+    fn set_pan(&mut self, pan: f32) {
+        self.pan = pan.max(-1.).min(1.);
+        self.update_gains();
+    }
+    fn update_gains(&mut self) {
+        let level = 1. / (self.unison as f32).sqrt();
+        for i in 0..self.unison {
+            let (l, r) = pan_gains(self.positions[i] + self.pan);
+            self.gains[i] = (l * level, r * level);
+        }
+        self.sub_gains = pan_gains(self.pan);
+    }
+    fn set_glide(&mut self, seconds: f32) {
+        for fm in self.fm.iter_mut() {
+            fm.set_glide(seconds);
+        }
+    }
+    // Rate of the FM section, when oversampling.
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        for fm in self.fm.iter_mut() {
+            fm.set_sample_rate(sample_rate);
+        }
+        self.sub_osc.set_sample_rate(sample_rate);
+        self.sub_level.set_sample_rate(sample_rate);
+    }
+    // [0, 1], of the sub-oscillator, a sine an octave below the carrier.
+    fn set_sub_level(&mut self, level: f32) {
+        self.sub_level.set_value(level.max(0.).min(1.));
+    }
+    // Of all the copies, for the pool to set them.
+    fn mod_envs(&mut self) -> impl Iterator<Item = &mut ADSR> {
+        self.fm.iter_mut().map(|fm| &mut fm.mod_env)
+    }
+    fn set_mod_env_amount(&mut self, amount: f32) {
+        for fm in self.fm.iter_mut() {
+            fm.set_mod_env_amount(amount);
+        }
+    }
+    // Each copy renders its left and right sides apart, instead of one signal panned.
+    fn set_stereo(&mut self, stereo: bool) {
+        self.stereo = stereo;
+        self.sides = [(0., 0.); MAX_UNISON];
+    }
+    // [0, 1], how far apart the modulators of the two sides are
+    fn set_stereo_spread(&mut self, spread: f32) {
+        let ratio = STEREO_FM_DETUNE * spread.max(0.).min(1.);
+        for (i, fm) in self.fm.iter_mut().enumerate() {
+            fm.set_modulator_detune(if i < MAX_UNISON { 1. - ratio } else { 1. + ratio });
+        }
+    }
+    fn set_cross_mod(&mut self, amount: f32) {
+        self.cross_mod = amount.max(0.).min(1.);
+    }
+    fn set_modulator_frequency(&mut self, frequency: f32) {
+        for fm in self.fm.iter_mut() {
+            fm.set_modulator_frequency(frequency);
+        }
+    }
+    fn set_ratio(&mut self, ratio: f32) {
+        for fm in self.fm.iter_mut() {
+            fm.set_ratio(ratio);
+        }
+    }
+    fn set_mod_index(&mut self, mod_index: f32) {
+        for fm in self.fm.iter_mut() {
+            fm.set_mod_index(mod_index);
+        }
+    }
+    fn set_feedback(&mut self, feedback: f32) {
+        for fm in self.fm.iter_mut() {
+            fm.set_feedback(feedback);
+        }
+    }
+    fn set_waveform(&mut self, waveform: Waveform) {
+        for fm in self.fm.iter_mut() {
+            fm.set_waveform(waveform);
+        }
+    }
+    fn set_wavetable(&mut self, wavetable: &WavetableOsc) {
+        for fm in self.fm.iter_mut() {
+            fm.set_wavetable(wavetable);
+        }
+    }
+    fn set_sine_table(&mut self, table: Option<&SineTable>) {
+        for fm in self.fm.iter_mut() {
+            fm.set_sine_table(table);
+        }
+        self.sub_osc.set_sine_table(table.cloned());
+    }
+    fn set_algorithm(&mut self, algorithm: Algorithm) {
+        for fm in self.fm.iter_mut() {
+            fm.set_algorithm(algorithm);
+        }
+    }
+    fn set_algorithm_morph(&mut self, morph: f32) {
+        for fm in self.fm.iter_mut() {
+            fm.set_algorithm_morph(morph);
+        }
+    }
+    fn set_fm_mode(&mut self, mode: FmMode) {
+        for fm in self.fm.iter_mut() {
+            fm.set_fm_mode(mode);
+        }
+    }
+    fn set_operator_ratio(&mut self, operator: usize, ratio: f32) {
+        for fm in self.fm.iter_mut() {
+            fm.set_operator_ratio(operator, ratio);
+        }
+    }
+    fn set_operator_level(&mut self, operator: usize, level: f32) {
+        for fm in self.fm.iter_mut() {
+            fm.set_operator_level(operator, level);
+        }
+    }
+}
+
+const VOICES: usize = 8;
+const MIDI_CHANNELS: usize = 16;
+
+const MAX_OVERSAMPLE: usize = 4;
+
+// Windowed-sinc low-pass, that decimates `factor` samples to one. Only the samples
+// that are kept are computed, which is the polyphase form of filtering and then
+// dropping samples.
+struct Decimator {
+    factor: usize,
+    taps: Vec<f32>,
+    history: Vec<f32>,
+    index: usize,
+}
+
+impl Decimator {
+    fn new(factor: usize) -> Decimator {
+        let length = 16 * factor;
+        // a bit below the new Nyquist frequency, in cycles per sample
+        let cutoff = 0.45 / factor as f32;
+        let center = (length - 1) as f32 / 2.;
+        let mut taps: Vec<f32> = (0..length)
+            .map(|n| {
+                let x = n as f32 - center;
+                let sinc = if x == 0. {
+                    2. * cutoff
+                } else {
+                    (2. * PI * cutoff * x).sin() / (PI * x)
+                };
+                // Blackman window
+                let w = 2. * PI * n as f32 / (length - 1) as f32;
+                sinc * (0.42 - 0.5 * w.cos() + 0.08 * (2. * w).cos())
+            })
+            .collect();
+        let sum: f32 = taps.iter().sum();
+        for tap in taps.iter_mut() {
+            *tap /= sum;
+        }
+        Decimator {
+            factor,
+            taps,
+            history: vec![0.; length],
+            index: 0,
+        }
+    }
+    // `input` holds `factor` samples.
+    fn process(&mut self, input: &[f32]) -> f32 {
+        let length = self.history.len();
+        for &s in input[..self.factor].iter() {
+            self.history[self.index] = s;
+            self.index = (self.index + 1) % length;
+        }
+        let mut out = 0.;
+        for (i, tap) in self.taps.iter().enumerate() {
+            out += tap * self.history[(self.index + i) % length];
+        }
+        return out;
+    }
+}
+
+// Which voice a new note takes when they are all playing. The quietest one is
+// usually the one that is the furthest into its release.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub enum StealPolicy {
+    Oldest,
+    Quietest,
+    Lowest,
+}
+
+struct VoicePool {
+    voices: Vec<Voice>,
+    now: usize,
+    steal_policy: StealPolicy,
+    // notes alternate left and right of `pan`, `pan_spread` apart
+    pan: f32,
+    pan_spread: f32,
+    next_side: f32,
+    // seconds, notes slide from the previous one when not 0
+    glide: f32,
+    last_frequency: Option<f32>,
+    sample_rate: f32,
+    oversample: usize,
+    // stereo decimators for 2x and 4x, allocated up front
+    decimators: Vec<[Decimator; 2]>,
+    // the voices playing are held, and no new note starts
+    drone: bool,
+    last_velocity: Option<f32>,
+    velocity_curve: VelCurve,
+    // [0, 1], how much each note varies, from `rng`, seeded by the control thread
+    humanize: f32,
+    rng: Noise,
+    // of the frequency of the notes, A4 at `tuning * A4`
+    tuning: f32,
+    // semitones, per MIDI channel
+    bends: [f32; MIDI_CHANNELS],
+    // of the next notes
+    envelope: EnvelopeMode,
+}
+
+impl VoicePool {
+    fn new(sample_rate: f32) -> VoicePool {
+        VoicePool {
+            sample_rate,
+            oversample: 1,
+            decimators: [2, 4]
+                .iter()
+                .map(|&f| [Decimator::new(f), Decimator::new(f)])
+                .collect(),
+            voices: (0..VOICES).map(|_| Voice::new(sample_rate)).collect(),
+            now: 0,
+            steal_policy: StealPolicy::Quietest,
+            pan: 0.,
+            pan_spread: 0.,
+            next_side: 1.,
+            glide: 0.,
+            last_frequency: None,
+            drone: false,
+            last_velocity: None,
+            velocity_curve: VelCurve::Linear,
+            humanize: 0.,
+            rng: Noise::new(NOISE_SEED),
+            tuning: 1.,
+            bends: [0.; MIDI_CHANNELS],
+            envelope: EnvelopeMode::Adsr,
+        }
+    }
+    // Sum of all active voices, oversampled if enabled.
+    fn render(&mut self, t: usize, pitch_mod: f32) -> (f32, f32) {
+        self.now = t;
+        if self.oversample == 1 {
+            return self.render_voices(t, pitch_mod);
+        }
+        let mut l = [0.; MAX_OVERSAMPLE];
+        let mut r = [0.; MAX_OVERSAMPLE];
+        for i in 0..self.oversample {
+            let (vl, vr) = self.render_voices(t, pitch_mod);
+            l[i] = vl;
+            r[i] = vr;
+        }
+        let decimators = &mut self.decimators[if self.oversample == 2 { 0 } else { 1 }];
+        return (decimators[0].process(&l), decimators[1].process(&r));
+    }
+    fn render_voices(&mut self, t: usize, pitch_mod: f32) -> (f32, f32) {
+        let (mut l, mut r) = (0., 0.);
+        for v in self.voices.iter_mut() {
+            let (vl, vr) = v.render(t, pitch_mod);
+            l += vl;
+            r += vr;
+        }
+        return (l, r);
+    }
+    // Sustains until note_off, bent with the other notes of `channel`.
+    fn note_on(&mut self, frequency: f32, velocity: f32, channel: u8) {
+        self.start(frequency, Some(self.velocity_curve.apply(velocity)), channel);
+    }
+    fn note_off(&mut self, frequency: f32) {
+        let now = self.now;
+        let frequency = frequency * self.tuning;
+        for v in self.voices.iter_mut() {
+            if v.active && v.frequency == frequency {
+                v.stop(now);
+            }
+        }
+    }
+    // One-shot note, released automatically after the hold stage.
+    fn trigger(&mut self, frequency: f32) {
+        self.start(frequency, None, 0);
+    }
+    fn start(&mut self, frequency: f32, velocity: Option<f32>, channel: u8) {
+        // something to hold first if nothing was playing
+        if self.drone && self.voices.iter().any(|v| v.active) {
+            return;
+        }
+        let now = self.now;
+        let frequency = frequency * self.tuning;
+        let (detune, attack_scale, level) = self.variation();
+        let note = Note {
+            frequency,
+            glide_from: if self.glide > 0. { self.last_frequency } else { None },
+            pan: self.next_pan(),
+            velocity,
+            detune,
+            attack_scale,
+            level,
+            channel,
+            bend: self.bends[channel as usize % MIDI_CHANNELS],
+            envelope: self.envelope,
+        };
+        self.last_frequency = Some(frequency);
+        self.last_velocity = Some(velocity.unwrap_or(1.));
+        match self.voices.iter().position(|v| !v.active && v.pending.is_none()) {
+            Some(i) => self.voices[i].play(now, note),
+            None => {
+                let i = self.steal();
+                self.voices[i].steal(now, note);
+            }
+        }
+    }
+    // The detune in cents, and the factors of the attack time and of the level, of
+    // the next note.
+    fn variation(&mut self) -> (f32, f32, f32) {
+        if self.humanize == 0. {
+            return (0., 1., 1.);
+        }
+        let h = self.humanize;
+        let detune = h * HUMANIZE_CENTS * self.rng.white();
+        let attack_scale = 1. + h * HUMANIZE_ATTACK * self.rng.white();
+        let level = 1. - h * HUMANIZE_LEVEL * (self.rng.white() + 1.) / 2.;
+        (detune, attack_scale, level)
+    }
+    // Applies from the next note, before the velocity scales the envelope and the
+    // modulation index.
+    fn set_velocity_curve(&mut self, curve: VelCurve) {
+        self.velocity_curve = curve;
+    }
+    // Hz, of A4, applies from the next note.
+    fn set_tuning(&mut self, a4: f32) {
+        if a4 > 0. && a4.is_finite() {
+            self.tuning = a4 / A4;
+        }
+    }
+    // [0, 1], applies from the next note.
+    fn set_humanize(&mut self, amount: f32) {
+        self.humanize = amount.max(0.).min(1.);
+    }
+    fn seed_humanize(&mut self, seed: u32) {
+        self.rng = Noise::new(seed);
+    }
+    fn next_pan(&mut self) -> f32 {
+        self.next_side = -self.next_side;
+        self.pan + self.next_side * self.pan_spread / 2.
+    }
+    // Pans the notes already playing as well.
+    fn set_pan(&mut self, pan: f32, spread: f32) {
+        self.pan = pan.max(-1.).min(1.);
+        self.pan_spread = spread.max(0.).min(2.);
+        for v in self.voices.iter_mut() {
+            v.set_pan(self.pan);
+        }
+    }
+    // The voice to steal when they are all playing, according to the policy.
+    fn steal(&self) -> usize {
+        let now = self.now;
+        let key = |v: &Voice| -> f32 {
+            match self.steal_policy {
+                StealPolicy::Oldest => v.start_time as f32,
+                StealPolicy::Quietest => v.level(now),
+                StealPolicy::Lowest => v.frequency,
+            }
+        };
+        let mut index = 0;
+        for (i, v) in self.voices.iter().enumerate() {
+            if key(v) < key(&self.voices[index]) {
+                index = i;
+            }
+        }
+        return index;
+    }
+    // Radians, or None for free-running oscillators.
+    fn set_retrigger_phase(&mut self, phase: Option<f32>) {
+        for v in self.voices.iter_mut() {
+            v.retrigger_phase = phase.map(|p| p.rem_euclid(2. * PI));
+        }
+    }
+    fn set_steal_policy(&mut self, policy: StealPolicy) {
+        self.steal_policy = policy;
+    }
+    // [0, 1], at 1 the modulation index is proportional to the velocity, at 0 it
+    // doesn't depend on it. Applies from the next note.
+    fn set_vel_to_mod(&mut self, amount: f32) {
+        for v in self.voices.iter_mut() {
+            v.vel_to_mod = amount.max(0.).min(1.);
+        }
+    }
+    fn set_sub_level(&mut self, level: f32) {
+        for v in self.voices.iter_mut() {
+            v.set_sub_level(level);
+        }
+    }
+    fn set_stereo(&mut self, stereo: bool) {
+        for v in self.voices.iter_mut() {
+            v.set_stereo(stereo);
+        }
+    }
+    fn set_stereo_spread(&mut self, spread: f32) {
+        for v in self.voices.iter_mut() {
+            v.set_stereo_spread(spread);
+        }
+    }
+    fn set_cross_mod(&mut self, amount: f32) {
+        for v in self.voices.iter_mut() {
+            v.set_cross_mod(amount);
+        }
+    }
+    // Drone mode: the envelopes stay at the sustain level, while the gate is open.
+    fn set_gate(&mut self, gate: bool) {
+        self.drone = gate;
+        let now = self.now;
+        for v in self.voices.iter_mut() {
+            v.set_gate(gate, now);
+        }
+    }
+    // All notes off: silences all the voices, drone included, after a short fade.
+    fn panic(&mut self) {
+        self.set_gate(false);
+        let now = self.now;
+        for v in self.voices.iter_mut() {
+            v.panic(now);
+        }
+    }
+    fn set_unison(&mut self, voices: usize, spread_cents: f32) {
+        for v in self.voices.iter_mut() {
+            v.set_unison(voices, spread_cents);
+        }
+    }
+    // 1, 2 or 4: the FM section runs at that multiple of the sample rate. This costs
+    // as much CPU, but removes the aliasing of bright patches.
+    fn set_oversample(&mut self, factor: u8) {
+        let factor = match factor {
+            0 | 1 => 1,
+            2 | 3 => 2,
+            _ => MAX_OVERSAMPLE,
+        };
+        if factor == self.oversample {
+            return;
+        }
+        self.oversample = factor;
+        let sample_rate = self.sample_rate * factor as f32;
+        for v in self.voices.iter_mut() {
+            v.set_sample_rate(sample_rate);
+        }
+    }
+    // The voices playing a note of `channel`, and the notes of that channel about to
+    // start, are bent, the others are left alone.
+    fn set_bend(&mut self, channel: u8, semitones: f32) {
+        match self.bends.get_mut(channel as usize) {
+            Some(bend) => *bend = semitones,
+            None => return,
+        }
+        for v in self.voices.iter_mut() {
+            if let Some((Some(ref mut note), _)) = v.pending {
+                if note.channel == channel {
+                    note.bend = semitones;
+                }
+            }
+            if v.channel == channel {
+                v.set_bend(semitones);
+            }
+        }
+    }
+    fn set_glide(&mut self, seconds: f32) {
+        self.glide = seconds.max(0.);
+        for v in self.voices.iter_mut() {
+            v.set_glide(self.glide);
+        }
+    }
+    fn set_modulator_frequency(&mut self, frequency: f32) {
+        for v in self.voices.iter_mut() {
+            v.set_modulator_frequency(frequency);
+        }
+    }
+    fn set_ratio(&mut self, ratio: f32) {
+        for v in self.voices.iter_mut() {
+            v.set_ratio(ratio);
+        }
+    }
+    fn set_mod_index(&mut self, mod_index: f32) {
+        for v in self.voices.iter_mut() {
+            v.set_mod_index(mod_index);
+        }
+    }
+    fn set_feedback(&mut self, feedback: f32) {
+        for v in self.voices.iter_mut() {
+            v.set_feedback(feedback);
+        }
+    }
+    fn set_waveform(&mut self, waveform: Waveform) {
+        for v in self.voices.iter_mut() {
+            v.set_waveform(waveform);
+        }
+    }
+    // Not real-time safe, to be called before starting the stream.
+    fn set_wavetable(&mut self, wavetable: &WavetableOsc) {
+        for v in self.voices.iter_mut() {
+            v.set_wavetable(wavetable);
+        }
+    }
+    fn set_sine_table(&mut self, table: Option<&SineTable>) {
+        for v in self.voices.iter_mut() {
+            v.set_sine_table(table);
+        }
+    }
+    fn set_algorithm(&mut self, algorithm: Algorithm) {
+        for v in self.voices.iter_mut() {
+            v.set_algorithm(algorithm);
+        }
+    }
+    fn set_algorithm_morph(&mut self, morph: f32) {
+        for v in self.voices.iter_mut() {
+            v.set_algorithm_morph(morph);
+        }
+    }
+    fn set_fm_mode(&mut self, mode: FmMode) {
+        for v in self.voices.iter_mut() {
+            v.set_fm_mode(mode);
+        }
+    }
+    fn set_operator_ratio(&mut self, operator: usize, ratio: f32) {
+        for v in self.voices.iter_mut() {
+            v.set_operator_ratio(operator, ratio);
+        }
+    }
+    fn set_operator_level(&mut self, operator: usize, level: f32) {
+        for v in self.voices.iter_mut() {
+            v.set_operator_level(operator, level);
+        }
+    }
+    fn set_attack(&mut self, attack: f32) {
+        for v in self.voices.iter_mut() {
+            v.env.set_attack(attack);
+        }
+    }
+    fn set_decay(&mut self, decay: f32) {
+        for v in self.voices.iter_mut() {
+            v.env.set_decay(decay);
+        }
+    }
+    fn set_hold(&mut self, hold: f32) {
+        for v in self.voices.iter_mut() {
+            v.env.set_hold(hold);
+        }
+    }
+    fn set_sustain(&mut self, sustain: f32) {
+        for v in self.voices.iter_mut() {
+            v.env.set_sustain(sustain.max(0.).min(1.));
+        }
+    }
+    fn set_release(&mut self, release: f32) {
+        for v in self.voices.iter_mut() {
+            v.env.set_release(release);
+            v.breakpoints.set_release(release);
+        }
+    }
+    fn set_curve(&mut self, curve: CurveShape) {
+        for v in self.voices.iter_mut() {
+            v.env.set_curve(curve);
+            v.breakpoints.set_curve(curve);
+        }
+    }
+    fn set_envelope_mode(&mut self, mode: EnvelopeMode) {
+        self.envelope = mode;
+    }
+    fn set_breakpoint(&mut self, i: usize, time: f32, level: f32) {
+        for v in self.voices.iter_mut() {
+            v.breakpoints.set_point(i, time, level);
+        }
+    }
+    fn set_breakpoint_count(&mut self, count: usize) {
+        for v in self.voices.iter_mut() {
+            v.breakpoints.set_point_count(count);
+        }
+    }
+    fn set_mod_env_attack(&mut self, attack: f32) {
+        for v in self.voices.iter_mut() {
+            v.mod_envs().for_each(|env| env.set_attack(attack));
+        }
+    }
+    fn set_mod_env_decay(&mut self, decay: f32) {
+        for v in self.voices.iter_mut() {
+            v.mod_envs().for_each(|env| env.set_decay(decay));
+        }
+    }
+    fn set_mod_env_sustain(&mut self, sustain: f32) {
+        for v in self.voices.iter_mut() {
+            v.mod_envs().for_each(|env| env.set_sustain(sustain.max(0.).min(1.)));
+        }
+    }
+    fn set_mod_env_release(&mut self, release: f32) {
+        for v in self.voices.iter_mut() {
+            v.mod_envs().for_each(|env| env.set_release(release));
+        }
+    }
+    fn set_mod_env_amount(&mut self, amount: f32) {
+        for v in self.voices.iter_mut() {
+            v.set_mod_env_amount(amount);
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum LfoTarget {
+    CarrierFreq,
+    Cutoff,
+    Amplitude,
+}
+
+// A note length, that a period of the LFO or the delay time can be synced to.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Division {
+    Whole,
+    Half,
+    DottedQuarter,
+    Quarter,
+    QuarterTriplet,
+    DottedEighth,
+    Eighth,
+    EighthTriplet,
+    Sixteenth,
+}
+
+impl Division {
+    fn beats(self) -> f64 {
+        match self {
+            Division::Whole => 4.,
+            Division::Half => 2.,
+            Division::DottedQuarter => 1.5,
+            Division::Quarter => 1.,
+            Division::QuarterTriplet => 2. / 3.,
+            Division::DottedEighth => 0.75,
+            Division::Eighth => 0.5,
+            Division::EighthTriplet => 1. / 3.,
+            Division::Sixteenth => 0.25,
+        }
+    }
+}
+
+// Sine LFO. The phase is kept when the rate changes. When synced, the phase follows
+// the beat of the sequencer instead, so that it stays on the grid.
+struct Lfo {
+    phase: f32,
+    rate: f32,
+    depth: f32,
+    sample_rate: f32,
+    sync: Option<Division>,
+    beat: f64,
+}
+
+impl Lfo {
+    fn new(sample_rate: f32) -> Lfo {
+        Lfo {
+            phase: 0.,
+            rate: 1.,
+            depth: 0.,
+            sample_rate,
+            sync: None,
+            beat: 0.,
+        }
+    }
+    // [-depth, depth]
+    fn render(&mut self) -> f32 {
+        if let Some(division) = self.sync {
+            self.phase = (self.beat / division.beats()).fract() as f32;
+        }
+        let v = (2. * PI * self.phase).sin() * self.depth;
+        self.phase += self.rate / self.sample_rate;
+        self.phase -= self.phase.floor();
+        return v;
+    }
+    // Hz
+    fn set_rate(&mut self, rate: f32) {
+        self.rate = rate;
+    }
+    // [0, 1]
+    fn set_depth(&mut self, depth: f32) {
+        self.depth = depth;
+    }
+    // A period per `division`, or free running at the rate when None.
+    fn set_sync(&mut self, sync: Option<Division>) {
+        self.sync = sync;
+    }
+    // Position in the sequence, for the sync, to be set before render().
+    fn set_beat(&mut self, beat: f64) {
+        self.beat = beat;
+    }
+}
+
+// Maximum delay time, in seconds.
+pub const MAX_DELAY: f32 = 2.;
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum DelayMode {
+    // both channels summed, the same echoes on both sides
+    Mono,
+    // a delay line per channel
+    Stereo,
+    // the echoes alternate between the left and right channels
+    PingPong,
+}
+
+// Stereo delay line. The buffers are allocated up front for MAX_DELAY, and the delay
+// time is smoothed, so that moving it bends the pitch, like a tape delay. When synced,
+// the delay time is a division at the tempo of the sequencer instead of `time`, and
+// follows it when it changes.
+struct Delay {
+    buffers: [Vec<f32>; 2],
+    write_index: usize,
+    // samples written since the mode changed, up to the length of the lines
+    written: usize,
+    delay_time: Param,
+    // seconds, when not synced
+    time: f32,
+    sync: Option<Division>,
+    tempo: f32,
+    feedback: f32,
+    mix: f32,
+    mode: DelayMode,
+    sample_rate: f32,
+}
+
+impl Delay {
+    fn new(sample_rate: f32) -> Delay {
+        let length = (MAX_DELAY * sample_rate) as usize + 2;
+        let mut delay_time = Param::new(sample_rate, 0.3);
+        delay_time.set_smoothing_time(0.1);
+        Delay {
+            buffers: [vec![0.; length], vec![0.; length]],
+            write_index: 0,
+            written: length,
+            delay_time,
+            time: 0.3,
+            sync: None,
+            tempo: TEMPO,
+            feedback: 0.3,
+            mix: 0.,
+            mode: DelayMode::Stereo,
+            sample_rate,
+        }
+    }
+    fn process(&mut self, l: f32, r: f32) -> (f32, f32) {
+        let length = self.buffers[0].len();
+        let delay = (self.delay_time.value() * self.sample_rate).max(1.);
+        let read = (self.write_index + length) as f32 - delay;
+        let index = read as usize;
+        let frac = read - index as f32;
+        // in samples, of what is read, what was written before the mode changed reads as
+        // silence, as if the lines had been cleared
+        let age = self.write_index + length - index;
+        let mut delayed = [0.; 2];
+        for (c, buffer) in self.buffers.iter().enumerate() {
+            let a = if age > self.written { 0. } else { buffer[index % length] };
+            let b = if age - 1 > self.written { 0. } else { buffer[(index + 1) % length] };
+            delayed[c] = a + (b - a) * frac;
+        }
+        let mono = (l + r) / 2.;
+        // what goes into each line: the input and the feedback of the same line, or of the
+        // other one in ping-pong, where the input only enters on the left
+        let written = match self.mode {
+            DelayMode::Mono => [mono + delayed[0] * self.feedback, 0.],
+            DelayMode::Stereo => [l + delayed[0] * self.feedback, r + delayed[1] * self.feedback],
+            DelayMode::PingPong => [mono + delayed[1] * self.feedback, delayed[0] * self.feedback],
+        };
+        if self.mode == DelayMode::Mono {
+            delayed[1] = delayed[0];
+        }
+        for (buffer, &w) in self.buffers.iter_mut().zip(written.iter()) {
+            buffer[self.write_index] = flush_denormal(w);
+        }
+        self.write_index = (self.write_index + 1) % length;
+        self.written = (self.written + 1).min(length);
+        return (l + (delayed[0] - l) * self.mix, r + (delayed[1] - r) * self.mix);
+    }
+    // The echoes of the previous mode are dropped, so that they don't come back on the
+    // wrong side. Clearing the lines would take too long for the audio thread, what they
+    // hold is ignored instead.
+    fn set_mode(&mut self, mode: DelayMode) {
+        if mode != self.mode {
+            self.mode = mode;
+            self.written = 0;
+        }
+    }
+    fn update_delay_time(&mut self) {
+        let time = match self.sync {
+            Some(division) => division.beats() as f32 * 60. / self.tempo,
+            None => self.time,
+        };
+        self.delay_time.set_value(time.max(0.).min(MAX_DELAY));
+    }
+    // seconds, up to MAX_DELAY, used when not synced
+    fn set_delay_time(&mut self, delay_time: f32) {
+        self.time = delay_time;
+        self.update_delay_time();
+    }
+    // A delay time of a `division`, clamped to MAX_DELAY at slow tempos, or the delay
+    // time in seconds when None.
+    fn set_delay_division(&mut self, sync: Option<Division>) {
+        self.sync = sync;
+        self.update_delay_time();
+    }
+    // beats per minute
+    fn set_tempo(&mut self, tempo: f32) {
+        self.tempo = tempo.max(1.);
+        self.update_delay_time();
+    }
+    // clamped below 1.0 to stay stable
+    fn set_feedback(&mut self, feedback: f32) {
+        self.feedback = feedback.max(0.).min(0.95);
+    }
+    // [0, 1], dry to wet
+    fn set_mix(&mut self, mix: f32) {
+        self.mix = mix.max(0.).min(1.);
+    }
+}
+
+const CHORUS_VOICES: usize = 3;
+// Center delay of the voices of the chorus, and how far the LFOs move them at full
+// depth, in seconds.
+const CHORUS_DELAY: f32 = 0.02;
+const CHORUS_RANGE: f32 = 0.005;
+
+// Chorus: copies of the input, delayed around CHORUS_DELAY by LFOs a third of a period
+// apart, the right channel moving the opposite way of the left one. The buffers are
+// allocated up front.
+struct Chorus {
+    buffers: [Vec<f32>; 2],
+    write_index: usize,
+    lfos: [Lfo; CHORUS_VOICES],
+    // [0, 1]
+    depth: f32,
+    mix: f32,
+    sample_rate: f32,
+}
+
+impl Chorus {
+    fn new(sample_rate: f32) -> Chorus {
+        let length = ((CHORUS_DELAY + CHORUS_RANGE) * sample_rate) as usize + 2;
+        let mut lfos = [Lfo::new(sample_rate), Lfo::new(sample_rate), Lfo::new(sample_rate)];
+        for (i, lfo) in lfos.iter_mut().enumerate() {
+            lfo.phase = i as f32 / CHORUS_VOICES as f32;
+            lfo.set_rate(0.8);
+            lfo.set_depth(1.);
+        }
+        Chorus {
+            buffers: [vec![0.; length], vec![0.; length]],
+            write_index: 0,
+            lfos,
+            depth: 0.5,
+            mix: 0.,
+            sample_rate,
+        }
+    }
+    fn process(&mut self, l: f32, r: f32) -> (f32, f32) {
+        let length = self.buffers[0].len();
+        // written even when dry, so that it doesn't start with old audio when turned up
+        self.buffers[0][self.write_index] = l;
+        self.buffers[1][self.write_index] = r;
+        let mut wet = [0.; 2];
+        if self.mix > 0. {
+            for lfo in self.lfos.iter_mut() {
+                let offset = lfo.render() * self.depth * CHORUS_RANGE;
+                for (c, &sign) in [1., -1.].iter().enumerate() {
+                    let delay = (CHORUS_DELAY + sign * offset) * self.sample_rate;
+                    let read = (self.write_index + length) as f32 - delay;
+                    let index = read as usize;
+                    let frac = read - index as f32;
+                    let buffer = &self.buffers[c];
+                    let a = buffer[index % length];
+                    let b = buffer[(index + 1) % length];
+                    wet[c] += (a + (b - a) * frac) / CHORUS_VOICES as f32;
+                }
+            }
+        }
+        self.write_index = (self.write_index + 1) % length;
+        if self.mix == 0. {
+            return (l, r);
+        }
+        return (l + (wet[0] - l) * self.mix, r + (wet[1] - r) * self.mix);
+    }
+    // Hz
+    fn set_rate(&mut self, rate: f32) {
+        for lfo in self.lfos.iter_mut() {
+            lfo.set_rate(rate.max(0.));
+        }
+    }
+    // [0, 1]
+    fn set_depth(&mut self, depth: f32) {
+        self.depth = depth.max(0.).min(1.);
+    }
+    // [0, 1], dry to wet
+    fn set_mix(&mut self, mix: f32) {
+        self.mix = mix.max(0.).min(1.);
+    }
+}
+
+// Lowpass-feedback comb filter of the reverb.
+struct Comb {
+    buffer: Vec<f32>,
+    index: usize,
+    filter_store: f32,
+}
+
+impl Comb {
+    fn new(length: usize) -> Comb {
+        Comb {
+            buffer: vec![0.; length.max(1)],
+            index: 0,
+            filter_store: 0.,
+        }
+    }
+    fn process(&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let output = self.buffer[self.index];
+        self.filter_store = flush_denormal(output * (1. - damping) + self.filter_store * damping);
+        self.buffer[self.index] = flush_denormal(input + self.filter_store * feedback);
+        self.index = (self.index + 1) % self.buffer.len();
+        return output;
+    }
+}
+
+struct Allpass {
+    buffer: Vec<f32>,
+    index: usize,
+}
+
+impl Allpass {
+    fn new(length: usize) -> Allpass {
+        Allpass {
+            buffer: vec![0.; length.max(1)],
+            index: 0,
+        }
+    }
+    fn process(&mut self, input: f32) -> f32 {
+        let buffered = self.buffer[self.index];
+        self.buffer[self.index] = flush_denormal(input + buffered * 0.5);
+        self.index = (self.index + 1) % self.buffer.len();
+        return buffered - input;
+    }
+}
+
+// Lengths of the Freeverb filters, in samples at 44.1kHz. The right channel is a
+// bit longer, to decorrelate the channels.
+const COMB_LENGTHS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASS_LENGTHS: [usize; 4] = [556, 441, 341, 225];
+const STEREO_SPREAD: usize = 23;
+
+// Freeverb: per channel, eight parallel combs then four allpasses in series. All the
+// buffers are allocated up front.
+struct Reverb {
+    combs: [Vec<Comb>; 2],
+    allpasses: [Vec<Allpass>; 2],
+    // comb feedback and damping, derived from the room size and damping
+    feedback: f32,
+    damping: f32,
+    mix: f32,
+}
+
+impl Reverb {
+    fn new(sample_rate: f32) -> Reverb {
+        let scale = |length: usize| (length as f32 * sample_rate / 44100.) as usize;
+        let channel = |spread: usize| -> (Vec<Comb>, Vec<Allpass>) {
+            (
+                COMB_LENGTHS.iter().map(|&l| Comb::new(scale(l + spread))).collect(),
+                ALLPASS_LENGTHS.iter().map(|&l| Allpass::new(scale(l + spread))).collect(),
+            )
+        };
+        let (combs_l, allpasses_l) = channel(0);
+        let (combs_r, allpasses_r) = channel(STEREO_SPREAD);
+        let mut reverb = Reverb {
+            combs: [combs_l, combs_r],
+            allpasses: [allpasses_l, allpasses_r],
+            feedback: 0.,
+            damping: 0.,
+            mix: 0.,
+        };
+        reverb.set_room_size(0.5);
+        reverb.set_damping(0.5);
+        return reverb;
+    }
+    fn process(&mut self, l: f32, r: f32) -> (f32, f32) {
+        // the input is attenuated, so that the sum of the combs doesn't clip
+        let input = (l + r) * 0.015;
+        let mut out = [0.; 2];
+        for c in 0..2 {
+            let mut s = 0.;
+            for comb in self.combs[c].iter_mut() {
+                s += comb.process(input, self.feedback, self.damping);
+            }
+            for allpass in self.allpasses[c].iter_mut() {
+                s = allpass.process(s);
+            }
+            out[c] = s;
+        }
+        return (l + (out[0] - l) * self.mix, r + (out[1] - r) * self.mix);
+    }
+    // [0, 1], longer decay
+    fn set_room_size(&mut self, room_size: f32) {
+        self.feedback = 0.7 + 0.28 * room_size.max(0.).min(1.);
+    }
+    // [0, 1], darker tail
+    fn set_damping(&mut self, damping: f32) {
+        self.damping = 0.4 * damping.max(0.).min(1.);
+    }
+    // [0, 1], dry to wet
+    fn set_mix(&mut self, mix: f32) {
+        self.mix = mix.max(0.).min(1.);
+    }
+}
+
+// Multiplies the signal by a sine, for metallic sidebands.
+struct RingMod {
+    osc: Oscillator,
+    mix: f32,
+}
+
+impl RingMod {
+    fn new(sample_rate: f32) -> RingMod {
+        let mut osc = Oscillator::new(sample_rate);
+        osc.set_frequency_no_smooth(440.);
+        RingMod { osc, mix: 0. }
+    }
+    fn process(&mut self, l: f32, r: f32) -> (f32, f32) {
+        // keeps running when dry, so that it doesn't click when brought in
+        let m = self.osc.render(0);
+        let g = 1. + (m - 1.) * self.mix;
+        return (l * g, r * g);
+    }
+    fn set_frequency(&mut self, frequency: f32) {
+        self.osc.set_frequency(frequency.max(0.));
+    }
+    // [0, 1], dry to wet
+    fn set_mix(&mut self, mix: f32) {
+        self.mix = mix.max(0.).min(1.);
+    }
+}
+
+// Lo-fi: quantizes the amplitude to `bits`, and holds each sample for `downsample`
+// samples. Both are fractional, so that they can be swept smoothly.
+struct BitCrusher {
+    bits: f32,
+    downsample: f32,
+    counter: f32,
+    held: (f32, f32),
+}
+
+impl BitCrusher {
+    fn new() -> BitCrusher {
+        BitCrusher {
+            bits: 24.,
+            downsample: 1.,
+            counter: 0.,
+            held: (0., 0.),
+        }
+    }
+    fn process(&mut self, l: f32, r: f32) -> (f32, f32) {
+        // transparent, don't bother
+        if self.bits >= 24. && self.downsample <= 1. {
+            return (l, r);
+        }
+        self.counter += 1.;
+        if self.counter >= self.downsample {
+            self.counter -= self.downsample;
+            self.held = (self.quantize(l), self.quantize(r));
+        }
+        return self.held;
+    }
+    // 2^bits levels evenly spaced in [-1, 1]
+    fn quantize(&self, x: f32) -> f32 {
+        let steps = self.bits.exp2() - 1.;
+        let x = x.max(-1.).min(1.);
+        ((x + 1.) / 2. * steps).round() / steps * 2. - 1.
+    }
+    // [1, 24]
+    fn set_bits(&mut self, bits: f32) {
+        self.bits = bits.max(1.).min(24.);
+    }
+    // 1 is the full rate
+    fn set_downsample(&mut self, factor: f32) {
+        self.downsample = factor.max(1.);
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ShaperCurve {
+    // soft saturation
+    Tanh,
+    HardClip,
+    // what goes over 1 is reflected back down, folding again each time it crosses -1
+    // or 1
+    Foldback,
+    // sin(x * pi / 2), rounder folds than Foldback
+    SineFold,
+}
+
+impl ShaperCurve {
+    // All the curves map the input in [-1, 1], whatever it is.
+    pub fn apply(self, x: f32) -> f32 {
+        match self {
+            ShaperCurve::Tanh => x.tanh(),
+            ShaperCurve::HardClip => x.max(-1.).min(1.),
+            ShaperCurve::Foldback => 1. - ((x + 1.).rem_euclid(4.) - 2.).abs(),
+            ShaperCurve::SineFold => (x * PI / 2.).sin(),
+        }
+    }
+}
+
+// Distortion through a transfer curve, between a gain in and a gain out, the output
+// staying in [-1, 1] whatever the input.
+struct Waveshaper {
+    curve: ShaperCurve,
+    drive: f32,
+    gain: f32,
+}
+
+impl Waveshaper {
+    fn new() -> Waveshaper {
+        Waveshaper {
+            curve: ShaperCurve::Tanh,
+            drive: 1.,
+            gain: 1.,
+        }
+    }
+    fn process(&self, sample: f32) -> f32 {
+        self.gain * self.curve.apply(self.drive * sample)
+    }
+    fn set_curve(&mut self, curve: ShaperCurve) {
+        self.curve = curve;
+    }
+    // >= 1, higher values saturate or fold more
+    fn set_drive(&mut self, drive: f32) {
+        self.drive = drive.max(1.);
+    }
+    // [0, 1], to make up for the level the drive adds
+    fn set_gain(&mut self, gain: f32) {
+        self.gain = gain.max(0.).min(1.);
+    }
+}
+
+// The voices, followed by a filter per channel, a delay, a waveshaper, and the
+// master gain.
+struct Synth {
+    pool: VoicePool,
+    // one per sample loaded, added to the voices
+    samples: Vec<SampleVoice>,
+    filters: [Filter; 2],
+    delay: Delay,
+    shaper: Waveshaper,
+    master_gain: Param,
+    cutoff: f32,
+    // 0 to 1, how much the cutoff follows the last note played
+    key_tracking: f32,
+    tracked_frequency: Option<f32>,
+    // 0 to 1, how much the velocity of the last note closes the cutoff
+    vel_to_cutoff: f32,
+    tracked_velocity: Option<f32>,
+    lfo: Lfo,
+    lfo_target: LfoTarget,
+    // [0, 1], balance between the sum of the voices and the effects chain
+    wet_amount: Param,
+    filter_enabled: bool,
+    chorus: Chorus,
+    chorus_enabled: bool,
+    delay_enabled: bool,
+    reverb: Reverb,
+    reverb_enabled: bool,
+    // on the sum of the voices
+    ring_mod: RingMod,
+    // on the master bus, after the dry/wet mix
+    crusher: BitCrusher,
+    crusher_enabled: bool,
+    // 0 is mono, 1 unchanged, above is wider
+    width: Param,
+    follower: EnvelopeFollower,
+    // [0, 1], how much the level of the voices opens the filter
+    auto_wah: f32,
+    // the cutoff has been moved away from `cutoff` by the LFO or the auto-wah
+    cutoff_modulated: bool,
+    // the effects alone, without the dry signal, of the last frame, for the rear
+    // channels when rendering in quad
+    rear: (f32, f32),
+    // on the master bus, before the waveshaper
+    transient_shaper: TransientShaper,
+    compressor: Compressor,
+}
+
+// How far down a soft note takes the cutoff, in octaves, with full velocity tracking.
+const VELOCITY_CUTOFF_OCTAVES: f32 = 4.;
+
+// Range of the auto-wah at full depth and full scale, in octaves above the cutoff.
+const AUTO_WAH_OCTAVES: f32 = 4.;
+
+// Level of a signal: a peak detector with separate attack and release times.
+struct EnvelopeFollower {
+    level: f32,
+    attack: f32,
+    release: f32,
+    sample_rate: f32,
+}
+
+impl EnvelopeFollower {
+    fn new(sample_rate: f32) -> EnvelopeFollower {
+        let mut follower = EnvelopeFollower {
+            level: 0.,
+            attack: 0.,
+            release: 0.,
+            sample_rate,
+        };
+        follower.set_attack(0.005);
+        follower.set_release(0.1);
+        return follower;
+    }
+    fn process(&mut self, input: f32) -> f32 {
+        let input = input.abs();
+        let coeff = if input > self.level { self.attack } else { self.release };
+        self.level = flush_denormal(input + (self.level - input) * coeff);
+        return self.level;
+    }
+    // seconds, time constants
+    fn set_attack(&mut self, attack: f32) {
+        self.attack = (-1. / (attack.max(1e-4) * self.sample_rate)).exp();
+    }
+    fn set_release(&mut self, release: f32) {
+        self.release = (-1. / (release.max(1e-4) * self.sample_rate)).exp();
+    }
+}
+
+// Feed-forward compressor: above `threshold`, the level is divided by `ratio`, in dB.
+// The level is detected on both channels, and the same gain applied to both, so that
+// the stereo image doesn't move.
+struct Compressor {
+    follower: EnvelopeFollower,
+    // dBFS
+    threshold: f32,
+    ratio: f32,
+    // dB, of the last sample
+    gain_reduction: f32,
+}
+
+impl Compressor {
+    fn new(sample_rate: f32) -> Compressor {
+        let mut follower = EnvelopeFollower::new(sample_rate);
+        follower.set_attack(0.01);
+        follower.set_release(0.1);
+        Compressor {
+            follower,
+            threshold: 0.,
+            ratio: 1.,
+            gain_reduction: 0.,
+        }
+    }
+    fn process(&mut self, l: f32, r: f32) -> (f32, f32) {
+        // transparent, don't bother
+        if self.ratio <= 1. {
+            self.gain_reduction = 0.;
+            return (l, r);
+        }
+        let level = self.follower.process(l.abs().max(r.abs()));
+        let over = 20. * level.max(1e-6).log10() - self.threshold;
+        self.gain_reduction = if over > 0. {
+            over * (1. - 1. / self.ratio)
+        } else {
+            0.
+        };
+        let gain = self.gain();
+        return (l * gain, r * gain);
+    }
+    // linear, of the last sample
+    fn gain(&self) -> f32 {
+        (10. as f32).powf(-self.gain_reduction / 20.)
+    }
+    fn gain_reduction(&self) -> f32 {
+        self.gain_reduction
+    }
+    // dBFS, [-60, 0]
+    fn set_threshold(&mut self, threshold: f32) {
+        self.threshold = threshold.max(-60.).min(0.);
+    }
+    // 1 is off
+    fn set_ratio(&mut self, ratio: f32) {
+        self.ratio = ratio.max(1.);
+    }
+    // seconds
+    fn set_attack(&mut self, attack: f32) {
+        self.follower.set_attack(attack);
+    }
+    fn set_release(&mut self, release: f32) {
+        self.follower.set_release(release);
+    }
+}
+
+// dB, the most a transient shaper boosts or cuts
+const TRANSIENT_RANGE: f32 = 24.;
+
+// Transient shaper: a fast and a slow envelope follower, the fast one is above the
+// slow one on the attacks, and below it as the sound decays. Their difference, in dB,
+// is scaled by `attack_gain` or `sustain_gain`, in [-1, 1], to boost or cut.
+struct TransientShaper {
+    fast: EnvelopeFollower,
+    slow: EnvelopeFollower,
+    attack_gain: f32,
+    sustain_gain: f32,
+    // linear, of the last sample
+    gain: f32,
+}
+
+impl TransientShaper {
+    fn new(sample_rate: f32) -> TransientShaper {
+        let mut fast = EnvelopeFollower::new(sample_rate);
+        fast.set_attack(0.001);
+        fast.set_release(0.02);
+        let mut slow = EnvelopeFollower::new(sample_rate);
+        slow.set_attack(0.02);
+        slow.set_release(0.2);
+        TransientShaper {
+            fast,
+            slow,
+            attack_gain: 0.,
+            sustain_gain: 0.,
+            gain: 1.,
+        }
+    }
+    fn process(&mut self, l: f32, r: f32) -> (f32, f32) {
+        // transparent, don't bother
+        if self.attack_gain == 0. && self.sustain_gain == 0. {
+            self.gain = 1.;
+            return (l, r);
+        }
+        let input = l.abs().max(r.abs());
+        let fast = self.fast.process(input).max(1e-6);
+        let slow = self.slow.process(input).max(1e-6);
+        let difference = 20. * (fast / slow).log10();
+        let db = if difference > 0. {
+            self.attack_gain * difference
+        } else {
+            -self.sustain_gain * difference
+        };
+        let db = db.max(-TRANSIENT_RANGE).min(TRANSIENT_RANGE);
+        self.gain = (10. as f32).powf(db / 20.);
+        return (l * self.gain, r * self.gain);
+    }
+    fn gain(&self) -> f32 {
+        self.gain
+    }
+    // [-1, 1], negative softens the attacks, positive makes them snappier
+    fn set_attack_gain(&mut self, gain: f32) {
+        self.attack_gain = gain.max(-1.).min(1.);
+    }
+    // [-1, 1], negative shortens the tails, positive brings them up
+    fn set_sustain_gain(&mut self, gain: f32) {
+        self.sustain_gain = gain.max(-1.).min(1.);
+    }
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub enum Effect {
+    Filter,
+    Chorus,
+    Delay,
+    Reverb,
+    BitCrusher,
+}
+
+// The note at which key tracking leaves the cutoff unchanged, middle C.
+const KEY_TRACKING_REFERENCE: f32 = 261.63;
+
+impl Synth {
+    fn new(sample_rate: f32) -> Synth {
+        Synth {
+            pool: VoicePool::new(sample_rate),
+            samples: Vec::new(),
+            filters: [Filter::new(sample_rate), Filter::new(sample_rate)],
+            delay: Delay::new(sample_rate),
+            shaper: Waveshaper::new(),
+            master_gain: Param::new(sample_rate, 0.5),
+            cutoff: 20000.,
+            key_tracking: 0.,
+            tracked_frequency: None,
+            vel_to_cutoff: 0.,
+            tracked_velocity: None,
+            lfo: Lfo::new(sample_rate),
+            lfo_target: LfoTarget::CarrierFreq,
+            wet_amount: Param::new(sample_rate, 1.),
+            filter_enabled: true,
+            chorus: Chorus::new(sample_rate),
+            chorus_enabled: true,
+            delay_enabled: true,
+            reverb: Reverb::new(sample_rate),
+            reverb_enabled: true,
+            ring_mod: RingMod::new(sample_rate),
+            crusher: BitCrusher::new(),
+            crusher_enabled: true,
+            width: Param::new(sample_rate, 1.),
+            follower: EnvelopeFollower::new(sample_rate),
+            auto_wah: 0.,
+            cutoff_modulated: false,
+            rear: (0., 0.),
+            transient_shaper: TransientShaper::new(sample_rate),
+            compressor: Compressor::new(sample_rate),
+        }
+    }
+    fn render(&mut self, t: usize) -> (f32, f32) {
+        // At full depth, the LFO modulates the pitch by an octave, the cutoff by four,
+        // and the amplitude down to silence.
+        let lfo = self.lfo.render();
+        if self.pool.last_frequency != self.tracked_frequency
+            || self.pool.last_velocity != self.tracked_velocity
+        {
+            self.tracked_frequency = self.pool.last_frequency;
+            self.tracked_velocity = self.pool.last_velocity;
+            let cutoff = self.cutoff;
+            self.set_cutoff(cutoff);
+        }
+        let mut pitch_mod = 1.;
+        let mut gain = 1.;
+        let mut cutoff_octaves = 0.;
+        match self.lfo_target {
+            LfoTarget::CarrierFreq => {
+                pitch_mod = lfo.exp2();
+            }
+            LfoTarget::Cutoff => {
+                cutoff_octaves = 4. * lfo;
+            }
+            LfoTarget::Amplitude => {
+                gain = 1. - (self.lfo.depth - lfo) / 2.;
+            }
+        }
+        let (l, r) = self.pool.render(t, pitch_mod);
+        let x: f32 = self.samples.iter_mut().map(|s| s.render(t)).sum();
+        let (l, r) = self.ring_mod.process(l + x, r + x);
+        // auto-wah: the louder, the brighter
+        let level = self.follower.process(l.abs().max(r.abs()));
+        cutoff_octaves += AUTO_WAH_OCTAVES * self.auto_wah * level.min(1.);
+        if cutoff_octaves != 0. || self.cutoff_modulated {
+            let cutoff = self.cutoff * self.tracking_factor() * cutoff_octaves.exp2();
+            for f in self.filters.iter_mut() {
+                f.set_cutoff(cutoff);
+            }
+            self.cutoff_modulated = cutoff_octaves != 0.;
+        }
+        let (dry_l, dry_r) = (gain * l, gain * r);
+        let (mut l, mut r) = (dry_l, dry_r);
+        if self.filter_enabled {
+            l = self.filters[0].process(l);
+            r = self.filters[1].process(r);
+        }
+        if self.chorus_enabled {
+            let (cl, cr) = self.chorus.process(l, r);
+            l = cl;
+            r = cr;
+        }
+        if self.delay_enabled {
+            let (dl, dr) = self.delay.process(l, r);
+            l = dl;
+            r = dr;
+        }
+        if self.reverb_enabled {
+            let (rl, rr) = self.reverb.process(l, r);
+            l = rl;
+            r = rr;
+        }
+        let wet = self.wet_amount.value();
+        let (rear_l, rear_r) = ((l - dry_l) * wet, (r - dry_r) * wet);
+        let mut l = dry_l + rear_l;
+        let mut r = dry_r + rear_r;
+        if self.crusher_enabled {
+            let (cl, cr) = self.crusher.process(l, r);
+            l = cl;
+            r = cr;
+        }
+        // mid/side, before the waveshaper that catches what widening pushes over
+        let width = self.width.value();
+        let mid = (l + r) / 2.;
+        let side = (l - r) / 2. * width;
+        let (l, r) = (mid + side, mid - side);
+        let (l, r) = self.transient_shaper.process(l, r);
+        let (l, r) = self.compressor.process(l, r);
+        let master_gain = self.master_gain.value();
+        let rear_gain = master_gain * self.transient_shaper.gain() * self.compressor.gain();
+        self.rear = (
+            sanitize(rear_gain * self.shaper.process(rear_l)),
+            sanitize(rear_gain * self.shaper.process(rear_r)),
+        );
+        return (
+            master_gain * self.shaper.process(l),
+            master_gain * self.shaper.process(r),
+        );
+    }
+    // `voices` detuned copies of each note, spread across the stereo field.
+    fn set_unison(&mut self, voices: usize, spread_cents: f32) {
+        self.pool.set_unison(voices, spread_cents);
+    }
+    fn set_cutoff(&mut self, cutoff: f32) {
+        self.cutoff = cutoff;
+        let cutoff = cutoff * self.tracking_factor();
+        for f in self.filters.iter_mut() {
+            f.set_cutoff(cutoff);
+        }
+    }
+    // At 1, the cutoff follows the pitch of the last note, at 0 it is fixed.
+    fn set_key_tracking(&mut self, amount: f32) {
+        self.key_tracking = amount.max(0.).min(1.);
+        let cutoff = self.cutoff;
+        self.set_cutoff(cutoff);
+    }
+    // At 1, a note at velocity 0 closes the cutoff by VELOCITY_CUTOFF_OCTAVES, and
+    // at full velocity leaves it.
+    fn set_vel_to_cutoff(&mut self, amount: f32) {
+        self.vel_to_cutoff = amount.max(0.).min(1.);
+        let cutoff = self.cutoff;
+        self.set_cutoff(cutoff);
+    }
+    // How much the last note moves the cutoff, from its pitch and velocity.
+    fn tracking_factor(&self) -> f32 {
+        let velocity = match self.tracked_velocity {
+            Some(v) if self.vel_to_cutoff > 0. => {
+                (-VELOCITY_CUTOFF_OCTAVES * self.vel_to_cutoff * (1. - v)).exp2()
+            }
+            _ => 1.,
+        };
+        self.key_tracking_factor() * velocity
+    }
+    fn key_tracking_factor(&self) -> f32 {
+        match self.tracked_frequency {
+            Some(f) if self.key_tracking > 0. => {
+                (f / KEY_TRACKING_REFERENCE).powf(self.key_tracking)
+            }
+            _ => 1.,
+        }
+    }
+    fn set_resonance(&mut self, resonance: f32) {
+        for f in self.filters.iter_mut() {
+            f.set_resonance(resonance);
+        }
+    }
+    fn set_width(&mut self, width: f32) {
+        self.width.set_value(width.max(0.).min(2.));
+    }
+    // Crossfades from the algorithm to the other one, instead of switching.
+    fn set_algorithm_morph(&mut self, morph: f32) {
+        self.pool.set_algorithm_morph(morph);
+    }
+    fn set_wet_amount(&mut self, wet: f32) {
+        self.wet_amount.set_value(wet.max(0.).min(1.));
+    }
+    // A disabled effect is bypassed, and costs nothing.
+    fn set_enabled(&mut self, effect: Effect, enabled: bool) {
+        match effect {
+            Effect::Filter => self.filter_enabled = enabled,
+            Effect::Chorus => self.chorus_enabled = enabled,
+            Effect::Delay => self.delay_enabled = enabled,
+            Effect::Reverb => self.reverb_enabled = enabled,
+            Effect::BitCrusher => self.crusher_enabled = enabled,
+        }
+    }
+    fn set_filter_type(&mut self, filter_type: FilterType) {
+        for f in self.filters.iter_mut() {
+            f.set_type(filter_type);
+        }
+    }
+    fn set_lfo_target(&mut self, target: LfoTarget) {
+        self.lfo_target = target;
+        // don't leave the cutoff where the LFO was
+        let cutoff = self.cutoff;
+        self.set_cutoff(cutoff);
+    }
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub enum Scale {
+    Chromatic,
+    Major,
+    Minor,
+    Pentatonic,
+}
+
+impl Scale {
+    // semitones above the root, within an octave
+    fn degrees(self) -> &'static [f32] {
+        match self {
+            Scale::Chromatic => &[0., 1., 2., 3., 4., 5., 6., 7., 8., 9., 10., 11.],
+            Scale::Major => &[0., 2., 4., 5., 7., 9., 11.],
+            Scale::Minor => &[0., 2., 3., 5., 7., 8., 10.],
+            Scale::Pentatonic => &[0., 2., 4., 7., 9.],
+        }
+    }
+}
+
+// The note of `scale` closest to `freq`, in any octave of `root`.
+pub fn quantize_freq(freq: f32, scale: Scale, root: f32) -> f32 {
+    if freq <= 0. || root <= 0. {
+        return freq;
+    }
+    let semitones = 12. * (freq / root).log2();
+    let octave = (semitones / 12.).floor();
+    let within = semitones - 12. * octave;
+    // the root of the next octave is a candidate too
+    let degree = scale
+        .degrees()
+        .iter()
+        .chain([12.].iter())
+        .cloned()
+        .fold(0., |best: f32, d| {
+            if (d - within).abs() < (best - within).abs() {
+                d
+            } else {
+                best
+            }
+        });
+    root * ((12. * octave + degree) / 12.).exp2()
+}
+
+pub const MAX_STEPS: usize = 64;
+
+// Plays a step per beat. The tempo can change while playing: the beat position
+// is accumulated from the frames elapsed, instead of computed from the start.
+struct Sequencer {
+    tempo: f32,
+    sample_rate: f32,
+    beat: f64,
+    last_frame: Option<usize>,
+    pattern: [Option<f32>; MAX_STEPS],
+    length: usize,
+    // frequency of the first note of the pattern, and what it is transposed to
+    reference: f32,
+    root: f32,
+    last_beat: Option<usize>,
+}
+
+impl Sequencer {
+    fn new(sample_rate: f32, tempo: f32) -> Sequencer {
+        Sequencer {
+            tempo,
+            sample_rate,
+            beat: 0.,
+            last_frame: None,
+            pattern: [None; MAX_STEPS],
+            length: 0,
+            reference: 0.,
+            root: 0.,
+            last_beat: None,
+        }
+    }
+    // A step is either a frequency or a rest. Truncated to MAX_STEPS.
+    fn set_pattern(&mut self, pattern: &[Option<f32>]) {
+        self.length = pattern.len().min(MAX_STEPS);
+        self.pattern[..self.length].copy_from_slice(&pattern[..self.length]);
+        self.reference = pattern.iter().flatten().next().cloned().unwrap_or(0.);
+    }
+    // Transpose the pattern so that its first note plays at `frequency`.
+    fn set_root(&mut self, frequency: f32) {
+        self.root = frequency;
+    }
+    // Turns step `i` into a rest, or into a note at the pitch of the pattern,
+    // extending the pattern if needed.
+    fn toggle_step(&mut self, i: usize) {
+        if i >= MAX_STEPS {
+            return;
+        }
+        if i >= self.length {
+            for step in self.pattern[self.length..=i].iter_mut() {
+                *step = None;
+            }
+            self.length = i + 1;
+        }
+        self.pattern[i] = match self.pattern[i] {
+            Some(_) => None,
+            None if self.reference > 0. => Some(self.reference),
+            None => {
+                self.reference = A4;
+                Some(self.reference)
+            }
+        };
+    }
+    fn set_tempo(&mut self, tempo: f32) {
+        self.tempo = tempo.max(1.);
+    }
+    fn beat(&self) -> f64 {
+        self.beat
+    }
+    // The step that starts at frame `t`, if any, and the frequency to play, if it is
+    // not a rest.
+    fn tick(&mut self, t: usize) -> Option<(usize, Option<f32>)> {
+        if let Some(last) = self.last_frame {
+            let elapsed = t.saturating_sub(last) as f64;
+            self.beat += elapsed / self.sample_rate as f64 * self.tempo as f64 / 60.;
+        }
+        self.last_frame = Some(t);
+        let beat = self.beat as usize;
+        if self.length == 0 || self.last_beat == Some(beat) {
+            return None;
+        }
+        self.last_beat = Some(beat);
+        let transpose = if self.root > 0. && self.reference > 0. {
+            self.root / self.reference
+        } else {
+            1.
+        };
+        let step = beat % self.length;
+        return Some((step, self.pattern[step].map(|f| f * transpose)));
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ArpMode {
+    Up,
+    Down,
+    UpDown,
+    Random,
+}
+
+const MAX_HELD: usize = 16;
+
+// Plays the notes held down one after the other, `rate` per beat, following the
+// beat of the sequencer. Each note lasts until the next one starts.
+struct Arpeggiator {
+    mode: ArpMode,
+    rate: u32,
+    // (frequency, velocity), sorted by frequency, played on the first MIDI channel
+    held: [(f32, f32); MAX_HELD],
+    count: usize,
+    // step of the pattern, and the last step played, in steps since the start
+    position: usize,
+    last_step: Option<usize>,
+    playing: Option<f32>,
+    noise: Noise,
+}
+
+impl Arpeggiator {
+    fn new() -> Arpeggiator {
+        Arpeggiator {
+            mode: ArpMode::Up,
+            rate: 1,
+            held: [(0., 0.); MAX_HELD],
+            count: 0,
+            position: 0,
+            last_step: None,
+            playing: None,
+            noise: Noise::new(NOISE_SEED),
+        }
+    }
+    fn set_mode(&mut self, mode: ArpMode) {
+        self.mode = mode;
+    }
+    // Steps per beat, e.g. 4 for sixteenth notes.
+    fn set_rate(&mut self, divisions: u32) {
+        self.rate = divisions.max(1);
+    }
+    fn hold(&mut self, frequency: f32, velocity: f32) {
+        if self.count == MAX_HELD || self.held[..self.count].iter().any(|h| h.0 == frequency) {
+            return;
+        }
+        let i = self.held[..self.count]
+            .iter()
+            .position(|h| h.0 > frequency)
+            .unwrap_or(self.count);
+        for j in (i..self.count).rev() {
+            self.held[j + 1] = self.held[j];
+        }
+        self.held[i] = (frequency, velocity);
+        self.count += 1;
+    }
+    fn release(&mut self, frequency: f32) {
+        if let Some(i) = self.held[..self.count].iter().position(|h| h.0 == frequency) {
+            for j in i..self.count - 1 {
+                self.held[j] = self.held[j + 1];
+            }
+            self.count -= 1;
+        }
+    }
+    // Forgets the notes held, returns the one sounding, to stop it.
+    fn stop(&mut self) -> Option<f32> {
+        self.count = 0;
+        self.position = 0;
+        self.playing.take()
+    }
+    // At `beat`, the note to stop and the note to start, if a step starts.
+    fn tick(&mut self, beat: f64) -> Option<(Option<f32>, Option<(f32, f32)>)> {
+        let step = (beat * self.rate as f64) as usize;
+        if self.last_step == Some(step) {
+            return None;
+        }
+        self.last_step = Some(step);
+        let stop = self.playing.take();
+        if self.count == 0 {
+            self.position = 0;
+            return stop.map(|f| (Some(f), None));
+        }
+        let n = self.count;
+        let i = match self.mode {
+            ArpMode::Up => self.position % n,
+            ArpMode::Down => n - 1 - self.position % n,
+            // the top and bottom notes are not repeated
+            ArpMode::UpDown if n > 1 => {
+                let p = self.position % (2 * n - 2);
+                if p < n {
+                    p
+                } else {
+                    2 * n - 2 - p
+                }
+            }
+            ArpMode::UpDown => 0,
+            ArpMode::Random => {
+                let r = (self.noise.white() + 1.) / 2.;
+                ((r * n as f32) as usize).min(n - 1)
+            }
+        };
+        self.position += 1;
+        let note = self.held[i];
+        self.playing = Some(note.0);
+        Some((stop, Some(note)))
+    }
+}
+
+const BEATS_PER_BAR: u64 = 4;
+// Hz, of the click on the first beat of a bar, and on the others
+const CLICK_FREQUENCIES: (f32, f32) = (1500., 1000.);
+// seconds, time constant of the decay of a click
+const CLICK_DECAY: f32 = 0.01;
+
+// Clicks on the beats of the sequencer, louder and higher on the first beat of each
+// bar. A decaying sine, mixed into the output after the master gain.
+struct Metronome {
+    enabled: bool,
+    level: f32,
+    last_beat: Option<u64>,
+    phase: f32,
+    phase_increment: f32,
+    amplitude: f32,
+    decay: f32,
+    sample_rate: f32,
+}
+
+impl Metronome {
+    fn new(sample_rate: f32) -> Metronome {
+        Metronome {
+            enabled: false,
+            level: 0.5,
+            last_beat: None,
+            phase: 0.,
+            phase_increment: 0.,
+            amplitude: 0.,
+            decay: (-1. / (CLICK_DECAY * sample_rate)).exp(),
+            sample_rate,
+        }
+    }
+    // `beat` is the position of the sequencer.
+    fn render(&mut self, beat: f64) -> f32 {
+        let current = beat.max(0.).floor() as u64;
+        if self.last_beat != Some(current) {
+            self.last_beat = Some(current);
+            let downbeat = current % BEATS_PER_BAR == 0;
+            let (amplitude, frequency) = if downbeat {
+                (1., CLICK_FREQUENCIES.0)
+            } else {
+                (0.5, CLICK_FREQUENCIES.1)
+            };
+            self.amplitude = amplitude;
+            self.phase = 0.;
+            self.phase_increment = 2. * PI * frequency / self.sample_rate;
+        }
+        if !self.enabled || self.amplitude < 1e-4 {
+            return 0.;
+        }
+        let s = self.amplitude * self.phase.sin();
+        self.phase += self.phase_increment;
+        self.amplitude *= self.decay;
+        s * self.level
+    }
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+    // [0, 1]
+    fn set_level(&mut self, level: f32) {
+        self.level = level.max(0.).min(1.);
+    }
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub enum Parameters {
+    CarrierFreq(f32),
+    ModulationFreq(f32),
+    Ratio(f32),
+    ModIndex(f32),
+    Feedback(f32),
+    Waveform(Waveform),
+    Algorithm(Algorithm),
+    FmMode(FmMode),
+    // operator, ratio to the carrier
+    OperatorRatio(usize, f32),
+    // operator, output level or modulation index
+    OperatorLevel(usize, f32),
+    Attack(f32),
+    Release(f32),
+    Decay(f32),
+    // seconds the sustain lasts for one-shot notes, e.g. from the sequencer
+    Hold(f32),
+    // [0, 1]
+    Sustain(f32),
+    Curve(CurveShape),
+    EnvelopeMode(EnvelopeMode),
+    // point of the breakpoint envelope: index, seconds since the start of the note, and
+    // level in [0, 1]. A point one past the last is added.
+    Breakpoint(usize, f32, f32),
+    // the points after this many are removed
+    BreakpointCount(usize),
+    // of the envelope of the modulation index, as Attack to Sustain, and how much it
+    // shapes it, in [0, 1]
+    ModEnvAttack(f32),
+    ModEnvDecay(f32),
+    ModEnvSustain(f32),
+    ModEnvRelease(f32),
+    ModEnvAmount(f32),
+    Cutoff(f32),
+    Resonance(f32),
+    FilterType(FilterType),
+    KeyTracking(f32),
+    StealPolicy(StealPolicy),
+    // start phase of the oscillators, in radians, or free-running
+    RetriggerPhase(Option<f32>),
+    // [0, 1], dry to wet
+    WetAmount(f32),
+    Enable(Effect, bool),
+    ReverbRoomSize(f32),
+    ReverbDamping(f32),
+    ReverbMix(f32),
+    // [0, 1], depth
+    AutoWah(f32),
+    // seconds
+    AutoWahAttack(f32),
+    AutoWahRelease(f32),
+    // bit crusher, 24 to 1 bits, and 1 to 64 times lower sample rate
+    Bits(f32),
+    Downsample(f32),
+    // stereo width, [0, 2]
+    Width(f32),
+    RingModFreq(f32),
+    RingModMix(f32),
+    // [0, 1], from the algorithm to the other one
+    AlgoMorph(f32),
+    // compressor: dBFS, ratio, attack and release in seconds
+    CompThreshold(f32),
+    CompRatio(f32),
+    CompAttack(f32),
+    CompRelease(f32),
+    // [0, 1], random variations of each note
+    Humanize(f32),
+    // of the random variations, chosen by the control thread
+    HumanizeSeed(u32),
+    // transient shaper, [-1, 1]: cut to boost the attacks, and the sustain
+    TransientAttack(f32),
+    TransientSustain(f32),
+    // chorus: Hz, [0, 1], [0, 1]
+    ChorusRate(f32),
+    ChorusDepth(f32),
+    ChorusMix(f32),
+    // [0, 1], how much the velocity scales the modulation index, and the cutoff
+    VelToMod(f32),
+    VelToCutoff(f32),
+    VelocityCurve(VelCurve),
+    // [0, 1], of the sub-oscillator
+    SubLevel(f32),
+    // the voices render their left and right sides apart, with their modulators
+    // StereoSpread apart, and modulating each other by CrossMod, both in [0, 1]
+    StereoFm(bool),
+    StereoSpread(f32),
+    CrossMod(f32),
+    // voices, spread in cents
+    Unison(usize, f32),
+    LfoRate(f32),
+    LfoDepth(f32),
+    LfoTarget(LfoTarget),
+    // the LFO follows the tempo, or its rate when None
+    LfoSync(Option<Division>),
+    DelayTime(f32),
+    DelayFeedback(f32),
+    DelayMix(f32),
+    DelayMode(DelayMode),
+    // the delay time is a division at the tempo, or DelayTime when None
+    DelaySync(Option<Division>),
+    // into the waveshaper of the master bus, which distorts through the curve, and out
+    Drive(f32),
+    ShaperCurve(ShaperCurve),
+    ShaperGain(f32),
+    MasterGain(f32),
+    // beats per minute
+    Tempo(f32),
+    // seconds, 0 is off
+    Glide(f32),
+    // 1, 2 or 4
+    Oversample(u8),
+    // MIDI channel counting from 0, semitones
+    PitchBend(u8, f32),
+    // pan in [-1, 1], spread in [0, 2] between consecutive notes
+    Pan(f32, f32),
+    // MIDI channel counting from 0, frequency, velocity in [0, 1]
+    NoteOn(u8, f32, f32),
+    NoteOff(f32),
+    // index of the sample, in the order they were added, velocity in [0, 1]
+    SampleOn(usize, f32),
+    // index of the sample, semitones
+    SamplePitch(usize, f32),
+    ToggleStep(usize),
+    // the notes held are arpeggiated instead of played together, or not
+    Arpeggiator(Option<ArpMode>),
+    // steps per beat
+    ArpRate(u32),
+    // clicks on the beats, and their level in [0, 1]
+    Metronome(bool),
+    MetronomeLevel(f32),
+    // holds the sound at the sustain level
+    Drone(bool),
+    // all notes off
+    Panic,
+}
+
+// Frequency of A4, that the notes are given at, whatever the tuning of the engine.
+pub const A4: f32 = 440.;
+
+// At A4, the engine retunes the notes it plays, see Engine::set_tuning.
+pub fn midi_to_freq(note: u8) -> f32 {
+    A4 * ((note as f32 - 69.) / 12.).exp2()
+}
+
+// Everything the audio thread renders. It doesn't depend on cubeb, so it can be
+// driven for any number of frames without an audio device.
+struct State {
+    synth: Synth,
+    sequencer: Sequencer,
+    arpeggiator: Arpeggiator,
+    arpeggiate: bool,
+    // parameters applied when each step starts, from the song
+    step_parameters: Vec<Vec<Parameters>>,
+    metronome: Metronome,
+}
+
+impl State {
+    fn new(sample_rate: f32) -> State {
+        State {
+            synth: Synth::new(sample_rate),
+            sequencer: Sequencer::new(sample_rate, TEMPO),
+            arpeggiator: Arpeggiator::new(),
+            arpeggiate: false,
+            step_parameters: Vec::new(),
+            metronome: Metronome::new(sample_rate),
+        }
+    }
+}
+
+// The frame at time `t`, in frames since the start.
+fn render_frame(state: &mut State, t: usize) -> Frame {
+    if let Some((step, note)) = state.sequencer.tick(t) {
+        // before the note, so that it plays with them
+        let count = state.step_parameters.get(step).map_or(0, |p| p.len());
+        for i in 0..count {
+            let p = state.step_parameters[step][i];
+            apply_parameter(state, p);
+        }
+        if let Some(freq) = note {
+            state.synth.pool.trigger(freq);
+        }
+    }
+    if state.arpeggiate {
+        if let Some((stop, start)) = state.arpeggiator.tick(state.sequencer.beat()) {
+            if let Some(freq) = stop {
+                state.synth.pool.note_off(freq);
+            }
+            if let Some((freq, velocity)) = start {
+                state.synth.pool.note_on(freq, velocity, 0);
+            }
+        }
+    }
+    state.synth.lfo.set_beat(state.sequencer.beat());
+    let (l, r) = state.synth.render(t);
+    let click = state.metronome.render(state.sequencer.beat());
+    let (l, r) = (l + click, r + click);
+    Frame {
+        l: sanitize(l),
+        r: sanitize(r),
+    }
+}
+
+// Last line of defense, so that a NaN never reaches the speakers or the recording.
+fn sanitize(x: f32) -> f32 {
+    if x.is_finite() {
+        x
+    } else {
+        0.
+    }
+}
+
+// Applies a parameter change, on the audio thread.
+fn apply_parameter(state: &mut State, m: Parameters) {
+    let synth = &mut state.synth;
+    let sequencer = &mut state.sequencer;
+    let arpeggiator = &mut state.arpeggiator;
+    match m {
+        Parameters::CarrierFreq(v) => {
+            sequencer.set_root(v);
+        }
+        Parameters::ModulationFreq(v) => {
+            synth.pool.set_modulator_frequency(v);
+        }
+        Parameters::Ratio(v) => {
+            synth.pool.set_ratio(v);
+        }
+        Parameters::ModIndex(v) => {
+            synth.pool.set_mod_index(v);
+        }
+        Parameters::Feedback(v) => {
+            synth.pool.set_feedback(v);
+        }
+        Parameters::Waveform(v) => {
+            synth.pool.set_waveform(v);
+        }
+        Parameters::Algorithm(v) => {
+            synth.pool.set_algorithm(v);
+        }
+        Parameters::FmMode(v) => {
+            synth.pool.set_fm_mode(v);
+        }
+        Parameters::OperatorRatio(op, v) => {
+            synth.pool.set_operator_ratio(op, v);
+        }
+        Parameters::OperatorLevel(op, v) => {
+            synth.pool.set_operator_level(op, v);
+        }
+        Parameters::Release(v) => {
+            synth.pool.set_release(v);
+        }
+        Parameters::Decay(v) => {
+            synth.pool.set_decay(v);
+        }
+        Parameters::Hold(v) => {
+            synth.pool.set_hold(v);
+        }
+        Parameters::Sustain(v) => {
+            synth.pool.set_sustain(v);
+        }
+        Parameters::Attack(v) => {
+            synth.pool.set_attack(v);
+        }
+        Parameters::Curve(v) => {
+            synth.pool.set_curve(v);
+        }
+        Parameters::EnvelopeMode(v) => {
+            synth.pool.set_envelope_mode(v);
+        }
+        Parameters::Breakpoint(i, time, level) => {
+            synth.pool.set_breakpoint(i, time, level);
+        }
+        Parameters::BreakpointCount(v) => {
+            synth.pool.set_breakpoint_count(v);
+        }
+        Parameters::ModEnvAttack(v) => {
+            synth.pool.set_mod_env_attack(v);
+        }
+        Parameters::ModEnvDecay(v) => {
+            synth.pool.set_mod_env_decay(v);
+        }
+        Parameters::ModEnvSustain(v) => {
+            synth.pool.set_mod_env_sustain(v);
+        }
+        Parameters::ModEnvRelease(v) => {
+            synth.pool.set_mod_env_release(v);
+        }
+        Parameters::ModEnvAmount(v) => {
+            synth.pool.set_mod_env_amount(v);
+        }
+        Parameters::Cutoff(v) => {
+            synth.set_cutoff(v);
+        }
+        Parameters::Resonance(v) => {
+            synth.set_resonance(v);
+        }
+        Parameters::FilterType(v) => {
+            synth.set_filter_type(v);
+        }
+        Parameters::KeyTracking(v) => {
+            synth.set_key_tracking(v);
+        }
+        Parameters::StealPolicy(v) => {
+            synth.pool.set_steal_policy(v);
+        }
+        Parameters::RetriggerPhase(v) => {
+            synth.pool.set_retrigger_phase(v);
+        }
+        Parameters::WetAmount(v) => {
+            synth.set_wet_amount(v);
+        }
+        Parameters::Enable(effect, enabled) => {
+            synth.set_enabled(effect, enabled);
+        }
+        Parameters::ReverbRoomSize(v) => {
+            synth.reverb.set_room_size(v);
+        }
+        Parameters::ReverbDamping(v) => {
+            synth.reverb.set_damping(v);
+        }
+        Parameters::ReverbMix(v) => {
+            synth.reverb.set_mix(v);
+        }
+        Parameters::AutoWah(v) => {
+            synth.auto_wah = v.max(0.).min(1.);
+        }
+        Parameters::AutoWahAttack(v) => {
+            synth.follower.set_attack(v);
+        }
+        Parameters::AutoWahRelease(v) => {
+            synth.follower.set_release(v);
+        }
+        Parameters::Bits(v) => {
+            synth.crusher.set_bits(v);
+        }
+        Parameters::Downsample(v) => {
+            synth.crusher.set_downsample(v);
+        }
+        Parameters::Width(v) => {
+            synth.set_width(v);
+        }
+        Parameters::AlgoMorph(v) => {
+            synth.set_algorithm_morph(v);
+        }
+        Parameters::CompThreshold(v) => {
+            synth.compressor.set_threshold(v);
+        }
+        Parameters::CompRatio(v) => {
+            synth.compressor.set_ratio(v);
+        }
+        Parameters::CompAttack(v) => {
+            synth.compressor.set_attack(v);
+        }
+        Parameters::CompRelease(v) => {
+            synth.compressor.set_release(v);
+        }
+        Parameters::Humanize(v) => {
+            synth.pool.set_humanize(v);
+        }
+        Parameters::HumanizeSeed(v) => {
+            synth.pool.seed_humanize(v);
+        }
+        Parameters::TransientAttack(v) => {
+            synth.transient_shaper.set_attack_gain(v);
+        }
+        Parameters::TransientSustain(v) => {
+            synth.transient_shaper.set_sustain_gain(v);
+        }
+        Parameters::ChorusRate(v) => {
+            synth.chorus.set_rate(v);
+        }
+        Parameters::ChorusDepth(v) => {
+            synth.chorus.set_depth(v);
+        }
+        Parameters::ChorusMix(v) => {
+            synth.chorus.set_mix(v);
+        }
+        Parameters::VelToMod(v) => {
+            synth.pool.set_vel_to_mod(v);
+        }
+        Parameters::VelToCutoff(v) => {
+            synth.set_vel_to_cutoff(v);
+        }
+        Parameters::VelocityCurve(v) => {
+            synth.pool.set_velocity_curve(v);
+        }
+        Parameters::SubLevel(v) => {
+            synth.pool.set_sub_level(v);
+        }
+        Parameters::StereoFm(v) => {
+            synth.pool.set_stereo(v);
+        }
+        Parameters::StereoSpread(v) => {
+            synth.pool.set_stereo_spread(v);
+        }
+        Parameters::CrossMod(v) => {
+            synth.pool.set_cross_mod(v);
+        }
+        Parameters::RingModFreq(v) => {
+            synth.ring_mod.set_frequency(v);
+        }
+        Parameters::RingModMix(v) => {
+            synth.ring_mod.set_mix(v);
+        }
+        Parameters::Unison(voices, spread) => {
+            synth.set_unison(voices, spread);
+        }
+        Parameters::LfoRate(v) => {
+            synth.lfo.set_rate(v);
+        }
+        Parameters::LfoDepth(v) => {
+            synth.lfo.set_depth(v);
+        }
+        Parameters::LfoSync(v) => {
+            synth.lfo.set_sync(v);
+        }
+        Parameters::LfoTarget(v) => {
+            synth.set_lfo_target(v);
+        }
+        Parameters::DelayTime(v) => {
+            synth.delay.set_delay_time(v);
+        }
+        Parameters::DelayFeedback(v) => {
+            synth.delay.set_feedback(v);
+        }
+        Parameters::DelayMix(v) => {
+            synth.delay.set_mix(v);
+        }
+        Parameters::DelayMode(v) => {
+            synth.delay.set_mode(v);
+        }
+        Parameters::DelaySync(v) => {
+            synth.delay.set_delay_division(v);
+        }
+        Parameters::Drive(v) => {
+            synth.shaper.set_drive(v);
+        }
+        Parameters::ShaperCurve(v) => {
+            synth.shaper.set_curve(v);
+        }
+        Parameters::ShaperGain(v) => {
+            synth.shaper.set_gain(v);
+        }
+        Parameters::MasterGain(v) => {
+            synth.master_gain.set_value(v);
+        }
+        Parameters::Tempo(v) => {
+            sequencer.set_tempo(v);
+            synth.delay.set_tempo(v);
+        }
+        Parameters::Glide(v) => {
+            synth.pool.set_glide(v);
+        }
+        Parameters::Oversample(v) => {
+            synth.pool.set_oversample(v);
+        }
+        Parameters::PitchBend(channel, v) => {
+            synth.pool.set_bend(channel, v);
+        }
+        Parameters::Pan(pan, spread) => {
+            synth.pool.set_pan(pan, spread);
+        }
+        Parameters::NoteOn(channel, v, velocity) => {
+            if state.arpeggiate {
+                arpeggiator.hold(v, velocity);
+            } else {
+                synth.pool.note_on(v, velocity, channel);
+            }
+        }
+        Parameters::SampleOn(i, velocity) => {
+            let now = synth.pool.now;
+            if let Some(sample) = synth.samples.get_mut(i) {
+                sample.trigger(now, velocity);
+            }
+        }
+        Parameters::SamplePitch(i, semitones) => {
+            if let Some(sample) = synth.samples.get_mut(i) {
+                sample.set_pitch(semitones);
+            }
+        }
+        Parameters::NoteOff(v) => {
+            if state.arpeggiate {
+                arpeggiator.release(v);
+            } else {
+                synth.pool.note_off(v);
+            }
+        }
+        Parameters::ToggleStep(i) => {
+            sequencer.toggle_step(i);
+        }
+        Parameters::Arpeggiator(mode) => {
+            match mode {
+                Some(mode) => arpeggiator.set_mode(mode),
+                None => {
+                    if let Some(freq) = arpeggiator.stop() {
+                        synth.pool.note_off(freq);
+                    }
+                }
+            }
+            state.arpeggiate = mode.is_some();
+        }
+        Parameters::ArpRate(v) => {
+            arpeggiator.set_rate(v);
+        }
+        Parameters::Metronome(v) => {
+            state.metronome.set_enabled(v);
+        }
+        Parameters::MetronomeLevel(v) => {
+            state.metronome.set_level(v);
+        }
+        Parameters::Drone(v) => {
+            synth.pool.set_gate(v);
+        }
+        Parameters::Panic => {
+            arpeggiator.stop();
+            synth.pool.panic();
+        }
+    }
+}
+
+// seconds, of the fade of the output when it starts and before it stops
+pub const FADE_TIME: f32 = 0.005;
+
+// Gain of the output, ramping linearly to 0 or 1 in FADE_TIME, so that it starts and
+// stops without a click.
+struct Fade {
+    gain: f32,
+    target: f32,
+    step: f32,
+}
+
+impl Fade {
+    fn new(sample_rate: f32) -> Fade {
+        Fade {
+            gain: 0.,
+            target: 1.,
+            step: 1. / (FADE_TIME * sample_rate),
+        }
+    }
+    // The gain of this frame, moved towards the target for the next one.
+    fn next(&mut self) -> f32 {
+        let gain = self.gain;
+        if self.gain < self.target {
+            self.gain = (self.gain + self.step).min(self.target);
+        } else if self.gain > self.target {
+            self.gain = (self.gain - self.step).max(self.target);
+        }
+        gain
+    }
+    fn fade_in(&mut self) {
+        self.gain = 0.;
+        self.target = 1.;
+    }
+    fn fade_out(&mut self) {
+        self.target = 0.;
+    }
+}
+
+// The synth, the sequencer and the arpeggiator, rendered a frame at a time. It
+// doesn't depend on an audio device, so that other programs can embed it.
+pub struct Engine {
+    state: State,
+    // frames rendered since the start
+    time: usize,
+    fade: Fade,
+    // of the last frame rendered
+    gain: f32,
+}
+
+impl Engine {
+    pub fn new(sample_rate: u32) -> Engine {
+        Engine {
+            state: State::new(sample_rate as f32),
+            time: 0,
+            fade: Fade::new(sample_rate as f32),
+            gain: 0.,
+        }
+    }
+    pub fn set_parameter(&mut self, p: Parameters) {
+        apply_parameter(&mut self.state, p);
+    }
+    // Hz, and velocity in [0, 1], on the first MIDI channel
+    pub fn note_on(&mut self, frequency: f32, velocity: f32) {
+        self.set_parameter(Parameters::NoteOn(0, frequency, velocity));
+    }
+    pub fn note_off(&mut self, frequency: f32) {
+        self.set_parameter(Parameters::NoteOff(frequency));
+    }
+    pub fn set_velocity_curve(&mut self, curve: VelCurve) {
+        self.set_parameter(Parameters::VelocityCurve(curve));
+    }
+    // Hz, of A4, 440 by default. The notes are given at A4 = 440Hz, from whatever
+    // source, and played retuned, from the next one.
+    pub fn set_tuning(&mut self, a4: f32) {
+        self.state.synth.pool.set_tuning(a4);
+    }
+    // A frequency per step of the sequencer, None for a rest.
+    pub fn set_pattern(&mut self, pattern: &[Option<f32>]) {
+        self.state.sequencer.set_pattern(pattern);
+    }
+    // Applied when each step starts, before its note.
+    pub fn set_step_parameters(&mut self, parameters: Vec<Vec<Parameters>>) {
+        self.state.step_parameters = parameters;
+    }
+    pub fn set_wavetable(&mut self, wavetable: &WavetableOsc) {
+        self.state.synth.pool.set_wavetable(wavetable);
+    }
+    // The sine of the voices is read from a table instead of computed, when enabled. Not
+    // on the audio thread, the table is allocated here.
+    pub fn set_use_sine_table(&mut self, enabled: bool) {
+        let table = if enabled { Some(SineTable::new()) } else { None };
+        self.state.synth.pool.set_sine_table(table.as_ref());
+    }
+    // Not on the audio thread, the voice is allocated here. Returns the index of the
+    // sample, for Parameters::SampleOn.
+    pub fn add_sample(&mut self, sample: &Sample) -> usize {
+        let sample_rate = self.state.synth.pool.sample_rate;
+        self.state.synth.samples.push(SampleVoice::new(sample.clone(), sample_rate));
+        self.state.synth.samples.len() - 1
+    }
+    pub fn process(&mut self, output: &mut [Frame]) {
+        for f in output.iter_mut() {
+            *f = self.render_frame();
+        }
+    }
+    // The next frame, of the front channels.
+    pub fn render_frame(&mut self) -> Frame {
+        let frame = render_frame(&mut self.state, self.time);
+        self.time += 1;
+        self.gain = self.fade.next();
+        Frame {
+            l: frame.l * self.gain,
+            r: frame.r * self.gain,
+        }
+    }
+    // The effects alone, in the last frame rendered, for the rear channels.
+    pub fn rear(&self) -> (f32, f32) {
+        let (l, r) = self.state.synth.rear;
+        (l * self.gain, r * self.gain)
+    }
+    // Silent, and fading in from the next frame, as when starting.
+    pub fn fade_in(&mut self) {
+        self.fade.fade_in();
+    }
+    // Silent after FADE_TIME.
+    pub fn fade_out(&mut self) {
+        self.fade.fade_out();
+    }
+    // dB, of the compressor, in the last frame rendered
+    pub fn gain_reduction(&self) -> f32 {
+        self.state.synth.compressor.gain_reduction()
+    }
+    // Position of the sequencer, in beats.
+    pub fn beat(&self) -> f64 {
+        self.state.sequencer.beat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustfft::num_complex::Complex;
+    use rustfft::FftPlanner;
+
+    const SAMPLE_RATE: f32 = 48000.;
+
+    // Energy of `signal` between a quarter of the sample rate and the Nyquist frequency,
+    // with a Hann window.
+    fn high_band_energy(signal: &[f32]) -> f32 {
+        let n = signal.len();
+        let mut spectrum: Vec<Complex<f32>> = signal
+            .iter()
+            .enumerate()
+            .map(|(i, x)| {
+                let w = 0.5 - 0.5 * (2. * PI * i as f32 / n as f32).cos();
+                Complex::new(x * w, 0.)
+            })
+            .collect();
+        FftPlanner::new().plan_fft_forward(n).process(&mut spectrum);
+        spectrum[n / 4..n / 2].iter().map(|c| c.norm_sqr()).sum()
+    }
+
+    fn oscillator(waveform: Waveform, frequency: f32) -> Oscillator {
+        let mut osc = Oscillator::new(SAMPLE_RATE);
+        osc.frequency.set_value_no_smooth(frequency);
+        osc.set_waveform(waveform);
+        osc
+    }
+
+    #[test]
+    fn polyblep_has_less_high_band_energy_than_naive() {
+        let frequency = 1234.;
+        let increment = 2. * PI * frequency / SAMPLE_RATE;
+        for &waveform in &[Waveform::Saw, Waveform::Square] {
+            let mut osc = oscillator(waveform, frequency);
+            let blep: Vec<f32> = (0..4096).map(|t| osc.render(t)).collect();
+            let naive: Vec<f32> = (0..4096)
+                .map(|t| {
+                    let phase = (t as f32 * increment) % (2. * PI);
+                    match waveform {
+                        Waveform::Saw => phase / PI - 1.,
+                        _ => {
+                            if phase < PI {
+                                1.
+                            } else {
+                                -1.
+                            }
+                        }
+                    }
+                })
+                .collect();
+            let (blep, naive) = (high_band_energy(&blep), high_band_energy(&naive));
+            assert!(blep < naive * 0.5, "{:?}: {} vs {}", waveform, blep, naive);
+        }
+    }
+
+    #[test]
+    fn waveshaper_keeps_a_hot_input_in_range() {
+        let mut shaper = Waveshaper::new();
+        shaper.set_curve(ShaperCurve::Tanh);
+        for &drive in &[1., 4., 50.] {
+            shaper.set_drive(drive);
+            for &x in &[2., -2.] {
+                let y = shaper.process(x);
+                assert!(y.abs() <= 1., "drive {}: {} -> {}", drive, x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn noise_with_the_same_seed_is_identical() {
+        let (mut a, mut b) = (Noise::new(1234), Noise::new(1234));
+        for _ in 0..1000 {
+            assert_eq!(a.white().to_bits(), b.white().to_bits());
+            assert_eq!(a.pink().to_bits(), b.pink().to_bits());
+        }
+    }
+
+    #[test]
+    fn impulse_tails_decay_to_zero() {
+        let mut filter = Filter::new(SAMPLE_RATE);
+        filter.set_cutoff(1000.);
+        filter.set_resonance(0.9);
+        filter.process(1.);
+        let tail = (0..48000).map(|_| filter.process(0.)).last().unwrap();
+        assert_eq!(tail, 0.);
+        assert_eq!((filter.ic1eq, filter.ic2eq), (0., 0.));
+
+        let mut delay = Delay::new(SAMPLE_RATE);
+        delay.delay_time.set_value_no_smooth(0.01);
+        delay.set_feedback(0.5);
+        delay.set_mix(1.);
+        delay.process(1., 1.);
+        // long enough for the whole lines to be written over
+        let tail = (0..4 * 48000).map(|_| delay.process(0., 0.)).last().unwrap();
+        assert_eq!(tail, (0., 0.));
+        assert!(delay.buffers.iter().all(|b| b.iter().all(|&x| x == 0.)));
+    }
+
+    #[test]
+    fn engine_renders_a_note() {
+        let mut engine = Engine::new(48000);
+        engine.note_on(440., 1.);
+        let mut output = vec![Frame { l: 0., r: 0. }; 4800];
+        engine.process(&mut output);
+        assert!(output.iter().all(|f| f.l.is_finite() && f.r.is_finite()));
+        let energy: f32 = output.iter().map(|f| f.l * f.l + f.r * f.r).sum();
+        assert!(energy > 1., "{}", energy);
+    }
+
+    #[test]
+    fn pitch_bend_only_bends_its_channel() {
+        let mut pool = VoicePool::new(SAMPLE_RATE);
+        pool.note_on(440., 1., 0);
+        pool.note_on(550., 1., 1);
+        pool.set_bend(1, 2.);
+        let bend = |pool: &VoicePool, frequency: f32| {
+            pool.voices.iter().find(|v| v.active && v.frequency == frequency).unwrap().bend
+        };
+        assert_eq!(bend(&pool, 440.), 0.);
+        assert_eq!(bend(&pool, 550.), 2.);
+        // the next notes of a bent channel start bent
+        pool.note_on(660., 1., 1);
+        assert_eq!(bend(&pool, 660.), 2.);
+    }
+
+    #[test]
+    fn retriggering_mid_release_does_not_jump() {
+        let mut env = ADSR::new(SAMPLE_RATE);
+        env.set_attack(0.01);
+        env.set_decay(0.1);
+        env.set_sustain(0.5);
+        env.set_release(0.2);
+        env.note_on(0, 1.);
+        env.note_off(9600);
+        let retrigger = 9600 + 4800;
+        let mut last = env.render(retrigger - 1);
+        assert!(last > 0.1);
+        env.note_on(retrigger, 1.);
+        // the steepest part of the attack, of 480 samples, moves by 2 / 480 per sample
+        for t in retrigger..retrigger + 9600 {
+            let v = env.render(t);
+            assert!((v - last).abs() < 0.01, "at {}: {} -> {}", t, last, v);
+            last = v;
+        }
+    }
+
+    #[test]
+    fn one_bit_has_two_levels() {
+        let mut crusher = BitCrusher::new();
+        crusher.set_bits(1.);
+        let mut levels: Vec<f32> = (0..=1000)
+            .map(|i| crusher.process(i as f32 / 500. - 1., 0.).0)
+            .collect();
+        levels.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        levels.dedup();
+        assert_eq!(levels, vec![-1., 1.]);
+    }
+
+    #[test]
+    fn zero_frequency_stays_finite() {
+        for &waveform in &[Waveform::Sine, Waveform::Saw, Waveform::Square, Waveform::Triangle] {
+            let mut osc = oscillator(waveform, 0.);
+            assert!((0..1000).all(|t| osc.render(t).is_finite()), "{:?}", waveform);
+        }
+        let mut engine = Engine::new(48000);
+        engine.set_parameter(Parameters::CarrierFreq(0.));
+        engine.note_on(0., 1.);
+        // before the output is sanitized
+        for t in 0..4800 {
+            let (l, r) = engine.state.synth.render(t);
+            assert!(l.is_finite() && r.is_finite(), "at {}", t);
+        }
+    }
+
+    #[test]
+    fn algorithm_morph_crossfades_both_routings() {
+        let voice = |morph: f32| {
+            let mut fm = FmVoice::new(SAMPLE_RATE);
+            fm.set_operator_level(2, 1.);
+            fm.morph.set_value_no_smooth(morph);
+            fm.note_on(0);
+            fm
+        };
+        let (mut stack, mut parallel, mut half) = (voice(0.), voice(1.), voice(0.5));
+        let mut difference = 0f32;
+        for t in 0..4800 {
+            let (a, b, m) = (stack.render(t, 1.), parallel.render(t, 1.), half.render(t, 1.));
+            assert!((m - (a + b) / 2.).abs() < 1e-5, "at {}: {} {} {}", t, a, b, m);
+            difference = difference.max((a - b).abs());
+        }
+        assert!(difference > 0.1);
+    }
+
+    // attack, decay, hold and release of 480, 960, 2400 and 4800 samples
+    fn one_shot() -> ADSR {
+        let mut env = ADSR::new(SAMPLE_RATE);
+        env.set_attack(0.01);
+        env.set_decay(0.02);
+        env.set_hold(0.05);
+        env.set_sustain(0.5);
+        env.set_release(0.1);
+        env
+    }
+
+    #[test]
+    fn adsr_stages() {
+        let start = 1000;
+        let mut env = one_shot();
+        env.trigger_with_velocity(start, 1.);
+        assert_eq!(env.render(start - 1), 0.);
+        assert_eq!(env.render(start), 0.);
+        assert!(env.render(start + 479) > 0.99);
+        assert!((env.render(start + 480) - 1.).abs() < 1e-6);
+        for t in start + 480 + 960..start + 480 + 960 + 2400 {
+            assert_eq!(env.render(t), 0.5);
+        }
+        let release_end = start + 480 + 960 + 2400 + 4800;
+        assert!(env.render(release_end - 1) < 0.001);
+        assert_eq!(env.render(release_end), 0.);
+        assert_eq!(env.render(release_end + 48000), 0.);
+        assert!(env.is_finished(release_end));
+    }
+
+    #[test]
+    fn adsr_zero_length_segments() {
+        let start = 1000;
+        let mut env = one_shot();
+        env.set_attack(0.);
+        env.set_decay(0.);
+        env.set_release(0.);
+        env.trigger_with_velocity(start, 1.);
+        assert_eq!(env.render(start - 1), 0.);
+        // straight to the sustain, and silent at the end of the hold
+        assert_eq!(env.render(start), 0.5);
+        assert_eq!(env.render(start + 2399), 0.5);
+        assert_eq!(env.render(start + 2400), 0.);
+    }
+
+    #[test]
+    fn breakpoints_from_adsr_match_it() {
+        let start = 1000;
+        let mut zero = one_shot();
+        zero.set_attack(0.);
+        zero.set_decay(0.);
+        for mut adsr in vec![one_shot(), zero] {
+            let mut envelope = BreakpointEnvelope::from_adsr(&adsr);
+            adsr.trigger_with_velocity(start, 0.8);
+            envelope.trigger_with_velocity(start, 0.8);
+            for t in 0..start + 480 + 960 + 2400 + 4800 + 100 {
+                let (a, b) = (adsr.render(t), envelope.render(t));
+                assert!((a - b).abs() < 1e-6, "at {}: {} {}", t, a, b);
+                assert_eq!(adsr.is_finished(t), envelope.is_finished(t), "at {}", t);
+            }
+        }
+    }
+
+    #[test]
+    fn breakpoints_sustain_until_note_off() {
+        let points = [(0., 0.), (0.01, 1.), (0.02, 0.25)];
+        let mut envelope = BreakpointEnvelope::new(SAMPLE_RATE, &points);
+        envelope.set_release(0.01);
+        envelope.note_on(0, 1.);
+        assert!((envelope.render(240) - 0.5).abs() < 1e-6);
+        assert_eq!(envelope.render(48000), 0.25);
+        assert!(!envelope.is_finished(48000));
+        envelope.note_off(48000);
+        assert_eq!(envelope.render(48000), 0.25);
+        assert!(envelope.render(48240) < 0.25);
+        assert!(envelope.is_finished(48480));
+        assert_eq!(envelope.render(48480), 0.);
+        // a point moved past its neighbour stays before it
+        envelope.set_point(1, 1., 1.);
+        assert_eq!(envelope.points[1].0, 960);
+        envelope.set_point_count(0);
+        envelope.trigger_with_velocity(0, 1.);
+        assert!(envelope.is_finished(0));
+    }
+
+    #[test]
+    fn param_reaches_its_target() {
+        let mut p = Param::new(SAMPLE_RATE, 0.);
+        p.set_value(1.);
+        assert!(p.is_smoothing());
+        let mut last = p.value();
+        assert!(last > 0. && last < 1.);
+        // five time constants of 10ms
+        for _ in 0..2400 {
+            let v = p.value();
+            assert!(v >= last);
+            last = v;
+        }
+        assert_eq!(last, 1.);
+        assert!(!p.is_smoothing());
+
+        let mut instant = Param::new(SAMPLE_RATE, 0.);
+        instant.set_smoothing_time(0.);
+        instant.set_value(1.);
+        assert!(!instant.is_smoothing());
+        assert_eq!(instant.value(), 1.);
+    }
+
+    #[test]
+    fn oscillator_settings_are_clamped() {
+        for &(frequency, clamped) in &[(-100., 0.), (1e9, SAMPLE_RATE / 2.)] {
+            let mut osc = Oscillator::new(SAMPLE_RATE);
+            osc.set_frequency(frequency);
+            assert_eq!(osc.frequency.v1, clamped);
+            assert!((0..4800).all(|t| osc.render(t).is_finite()), "{}", frequency);
+        }
+        let mut osc = Oscillator::new(SAMPLE_RATE);
+        osc.set_detune(5000.);
+        assert_eq!(osc.detune.v1, 1200.);
+        osc.set_detune(-5000.);
+        assert_eq!(osc.detune.v1, -1200.);
+    }
+
+    #[test]
+    fn changing_the_delay_mode_drops_the_echoes() {
+        let mut delay = Delay::new(SAMPLE_RATE);
+        delay.delay_time.set_value_no_smooth(0.01);
+        delay.set_feedback(0.5);
+        delay.set_mix(1.);
+        delay.process(1., 0.);
+        for _ in 0..100 {
+            delay.process(0., 0.);
+        }
+        delay.set_mode(DelayMode::PingPong);
+        assert!((0..4800).all(|_| delay.process(0., 0.) == (0., 0.)));
+        // and the new ones are heard, 480 samples later
+        delay.process(1., 1.);
+        let echo = (0..480).map(|_| delay.process(0., 0.)).last().unwrap();
+        assert_eq!(echo, (1., 0.));
+    }
+
+    #[test]
+    fn velocity_curves_keep_the_ends() {
+        for &curve in &[VelCurve::Linear, VelCurve::Soft, VelCurve::Hard] {
+            assert_eq!(curve.apply(0.), 0., "{:?}", curve);
+            assert_eq!(curve.apply(1.), 1., "{:?}", curve);
+        }
+    }
+
+    #[test]
+    fn output_fades_in_from_zero() {
+        let mut fade = Fade::new(SAMPLE_RATE);
+        // of a constant input of 1
+        let ramp: Vec<f32> = (0..480).map(|_| fade.next()).collect();
+        assert_eq!(ramp[0], 0.);
+        assert!(ramp.windows(2).all(|w| w[1] >= w[0]));
+        // FADE_TIME at 48kHz
+        assert!(ramp[239] < 1.);
+        assert!((ramp[240] - 1.).abs() < 1e-4);
+        assert_eq!(ramp[479], 1.);
+
+        let mut engine = Engine::new(48000);
+        engine.note_on(440., 1.);
+        let first = engine.render_frame();
+        assert_eq!((first.l, first.r), (0., 0.));
+        let mut output = vec![Frame { l: 0., r: 0. }; 480];
+        engine.process(&mut output);
+        assert!(output.iter().any(|f| f.l != 0.));
+        engine.fade_out();
+        engine.process(&mut output);
+        // FADE_TIME, and a frame of rounding
+        assert!(output[241..].iter().all(|f| f.l == 0. && f.r == 0.));
+    }
+
+    #[test]
+    fn sine_table_matches_sin() {
+        // the error of the linear interpolation, see SINE_TABLE_SIZE
+        let tolerance = 1e-6;
+        let table = SineTable::new();
+        for i in 0..=100_000 {
+            let phase = i as f32 / 100_000.;
+            let error = (table.render(phase) - (2. * PI * phase).sin()).abs();
+            assert!(error < tolerance, "at {}: {}", phase, error);
+        }
+        let mut with_table = oscillator(Waveform::Sine, 1234.5);
+        with_table.set_sine_table(Some(table));
+        let mut without = oscillator(Waveform::Sine, 1234.5);
+        for t in 0..48000 {
+            let error = (with_table.render(t) - without.render(t)).abs();
+            assert!(error < tolerance, "at {}: {}", t, error);
+        }
+    }
+
+    #[test]
+    fn shaper_curves_stay_in_range() {
+        for i in 0..=2000 {
+            let x = i as f32 / 100. - 10.;
+            for &curve in &[ShaperCurve::Tanh, ShaperCurve::HardClip, ShaperCurve::SineFold] {
+                assert!(curve.apply(x).abs() <= 1., "{:?} at {}", curve, x);
+            }
+            let folded = ShaperCurve::Foldback.apply(x);
+            assert!(folded.abs() <= 1., "Foldback at {}", x);
+            // up to the next peak of the folds, at 5
+            if x > 1. && x < 5. {
+                assert!(folded < 1., "Foldback at {}: {}", x, folded);
+            }
+        }
+        // reflected down by as much as it goes over
+        assert!((ShaperCurve::Foldback.apply(1.5) - 0.5).abs() < 1e-6);
+    }
+}
@@ -1,318 +1,1043 @@
-use cubeb::StereoFrame;
-use std::f32::consts::PI;
+use cubeb::MonoFrame;
+use std::env;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::Arc;
 
-use audio_clock::audio_clock;
-use monome::{Monome, MonomeEvent};
+use audio_clock::{audio_clock, ClockConsumer, ClockUpdater};
+use monome::{KeyDirection, Monome, MonomeDeviceType, MonomeEvent};
 use std::{thread, time};
 use crossbeam::queue::ArrayQueue;
+use std::path::Path;
+use std::sync::Mutex;
 
+use redw::arc::{
+    freq_to_encoder, initial_leds, load_mapping, parse_mapping, restore_arc_state,
+    save_arc_state, turn_encoder, FreqCurve, ARC_STATE_PATH, DEFAULT_MAPPING, METER_PAGE, PAGES,
+};
+use redw::control::{
+    listen, open_midi, open_osc, snapshot_parameter, Automation, BeatClock, Controls, Event,
+    ParamSnapshot, ParameterState, SNAPSHOT_SLOTS,
+};
+use redw::patch::{load_patch, load_song, save_patch, startup_engine, Patch};
+use redw::record::{render_offline, Analyzer, RecordFormat, Recorder};
+use redw::{midi_to_freq, Engine, Error, Frame, Parameters, FADE_TIME, TEMPO};
+
+// used if the backend can't tell its preferred rate
 const SAMPLE_FREQUENCY: u32 = 48_000;
 const STREAM_FORMAT: cubeb::SampleFormat = cubeb::SampleFormat::Float32NE;
 
-type Frame = StereoFrame<f32>;
-
-#[derive(Debug)]
-struct ADSR {
-    start_time: usize,
-    attack: usize,
-    decay: usize,
-    hold: usize,
-    release: usize,
-    sample_rate: f32,
-    sustain: f32,
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+struct QuadFrame {
+    fl: f32,
+    fr: f32,
+    rl: f32,
+    rr: f32,
 }
 
-impl ADSR {
-    pub fn new(sample_rate: f32) -> ADSR {
-        ADSR {
-            start_time: 0,
-            attack: 0,
-            decay: 0,
-            hold: 0,
-            release: (sample_rate / 100.) as usize,
-            sample_rate,
-            sustain: 1.0,
-        }
-    }
-    pub fn render(&mut self, t: usize) -> f32 {
-        // shaping: [0,1] -> [0,1]
-        let f = |t: f32| -> f32 {
-            return t * t;
-        };
-        if t > self.start_time + self.duration() || t < self.start_time {
-            return 0.0;
-        }
-        let mut t = t - self.start_time;
-        if t < self.attack {
-            return f(t as f32 / self.attack as f32);
+impl cubeb::Frame for QuadFrame {}
+
+// What the audio callback can write, built from the front and rear pairs the synth
+// renders.
+trait OutputFrame: cubeb::Frame + Copy + Send + 'static {
+    const LAYOUT: cubeb::ChannelLayout;
+    fn new(front: Frame, rear: (f32, f32)) -> Self;
+}
+
+impl OutputFrame for MonoFrame<f32> {
+    const LAYOUT: cubeb::ChannelLayout = cubeb::ChannelLayout::MONO;
+    fn new(front: Frame, _: (f32, f32)) -> Self {
+        MonoFrame {
+            m: (front.l + front.r) / 2.,
         }
-        t -= self.attack;
-        if t < self.decay {
-            return 1.0 - (1.0 - self.sustain) * f((t as f32) / (self.decay) as f32);
+    }
+}
+
+impl OutputFrame for Frame {
+    const LAYOUT: cubeb::ChannelLayout = cubeb::ChannelLayout::STEREO;
+    fn new(front: Frame, _: (f32, f32)) -> Self {
+        front
+    }
+}
+
+impl OutputFrame for QuadFrame {
+    const LAYOUT: cubeb::ChannelLayout = cubeb::ChannelLayout::QUAD;
+    fn new(front: Frame, rear: (f32, f32)) -> Self {
+        QuadFrame {
+            fl: front.l,
+            fr: front.r,
+            rl: rear.0,
+            rr: rear.1,
         }
-        t -= self.decay;
-        if t < self.hold {
-            return self.sustain;
+    }
+}
+
+// The bottom row of a grid edits the first steps of the sequencer.
+const GRID_STEPS: usize = 16;
+// The rows in between are a keyboard: a semitone per column, a fourth per row, from
+// this note at the bottom left.
+const GRID_ROOT: u8 = 36;
+
+// The MIDI note of a key of the grid keyboard, if it is one.
+fn grid_note(x: i32, y: i32, height: i32) -> Option<u8> {
+    if y < 1 || y >= height - 1 || x < 0 {
+        return None;
+    }
+    let note = GRID_ROOT as i32 + x + 5 * (height - 2 - y);
+    if note > 127 {
+        return None;
+    }
+    Some(note as u8)
+}
+
+// Lights the keys of the notes held, wherever they are on the keyboard.
+fn draw_keyboard(monome: &mut Monome, held: &[u8; 128]) {
+    let height = monome.height() as i32;
+    for y in 1..height - 1 {
+        for x in 0..monome.width() as i32 {
+            if let Some(note) = grid_note(x, y, height) {
+                monome.set(x, y, if held[note as usize] > 0 { 15 } else { 0 });
+            }
         }
-        t -= self.hold;
-        return self.sustain - self.sustain * f(t as f32 / (self.release) as f32);
     }
-    pub fn set_attack(&mut self, attack: f32) {
-        self.attack = self.s2f(attack);
+}
+
+// The record and play keys, lit when recording or playing.
+fn draw_automation(monome: &mut Monome, automation: &Automation) {
+    monome.set(2, 0, if automation.is_recording() { 15 } else { 0 });
+    monome.set(3, 0, if automation.is_playing() { 15 } else { 0 });
+}
+
+fn draw_steps(monome: &mut Monome, steps: &[bool], length: usize, playhead: usize) {
+    let y = monome.height() as i32 - 1;
+    for (x, &on) in steps.iter().enumerate() {
+        let level = if x == playhead {
+            15
+        } else if x < length && on {
+            8
+        } else {
+            0
+        };
+        monome.set(x as i32, y, level);
     }
-    pub fn set_decay(&mut self, decay: f32) {
-        self.decay = self.s2f(decay);
+}
+
+// Peak and RMS level of the last buffer rendered, over both front channels, written by
+// the audio thread.
+struct Meter {
+    peak: AtomicU32,
+    rms: AtomicU32,
+    // dB, the most the compressor reduced the gain during the buffer
+    gain_reduction: AtomicU32,
+}
+
+impl Meter {
+    fn new() -> Meter {
+        Meter {
+            peak: AtomicU32::new(0),
+            rms: AtomicU32::new(0),
+            gain_reduction: AtomicU32::new(0),
+        }
     }
-    pub fn set_hold(&mut self, hold: f32) {
-        self.hold = self.s2f(hold);
+    fn set_gain_reduction(&self, db: f32) {
+        self.gain_reduction.store(db.to_bits(), Ordering::Relaxed);
     }
-    pub fn set_sustain(&mut self, sustain: f32) {
-        self.sustain = sustain;
+    fn gain_reduction(&self) -> f32 {
+        f32::from_bits(self.gain_reduction.load(Ordering::Relaxed))
     }
-    pub fn set_release(&mut self, release: f32) {
-        self.release = self.s2f(release);
+    // `sum` of the squares of the `samples` samples of the buffer
+    fn update(&self, peak: f32, sum: f32, samples: usize) {
+        let rms = (sum / samples.max(1) as f32).sqrt();
+        self.peak.store(peak.to_bits(), Ordering::Relaxed);
+        self.rms.store(rms.to_bits(), Ordering::Relaxed);
     }
-    pub fn trigger(&mut self, time: usize) {
-        self.start_time = time;
+    // (peak, rms), linear
+    fn levels(&self) -> (f32, f32) {
+        (
+            f32::from_bits(self.peak.load(Ordering::Relaxed)),
+            f32::from_bits(self.rms.load(Ordering::Relaxed)),
+        )
     }
-    fn duration(&self) -> usize {
-        self.attack + self.decay + self.hold + self.release
+}
+
+// Fraction of the buffer duration spent rendering it, averaged over the last few
+// callbacks. Above 1, the callback can't keep up and the output glitches.
+struct Load {
+    load: AtomicU32,
+}
+
+impl Load {
+    fn new() -> Load {
+        Load {
+            load: AtomicU32::new(0),
+        }
     }
-    fn f2s(&self, t: usize) -> f32 {
-        (t as f32) / self.sample_rate
+    fn update(&self, elapsed: time::Duration, frames: usize, sample_rate: u32) {
+        let budget = frames as f32 / sample_rate as f32;
+        let load = elapsed.as_secs_f32() / budget;
+        let average = f32::from_bits(self.load.load(Ordering::Relaxed));
+        let average = average + (load - average) * 0.1;
+        self.load.store(average.to_bits(), Ordering::Relaxed);
     }
-    fn s2f(&self, s: f32) -> usize {
-        (s * self.sample_rate) as usize
+    fn load(&self) -> f32 {
+        f32::from_bits(self.load.load(Ordering::Relaxed))
     }
 }
 
-struct Param {
-    v0: f32,
-    v1: f32,
-    counter: isize,
-    smoothing: isize,
-    sample_rate: f32,
+// Range of the meter display, in dB below full scale, and how fast it falls back.
+const METER_RANGE: f32 = 60.;
+const METER_DECAY: f32 = 20.;
+// gain reduction of the compressor shown by a full ring, in dB
+const GAIN_REDUCTION_RANGE: f32 = 24.;
+
+// Number of LEDs of a ring lit for `db`.
+fn meter_leds(db: f32) -> usize {
+    ((1. + db / METER_RANGE).max(0.).min(1.) * 64.) as usize
+}
+
+// Everything the audio callback owns or shares with the other threads.
+struct AudioThread {
+    engine: Engine,
+    snapshot: Arc<ParamSnapshot>,
+    latest: [f32; SNAPSHOT_SLOTS],
+    applied: [f32; SNAPSHOT_SLOTS],
+    queue: Arc<ArrayQueue<Event>>,
+    // events waiting for their frame, allocated up front
+    scheduled: Vec<Event>,
+    clock: ClockConsumer,
+    updater: ClockUpdater,
+    record_queue: Option<Arc<ArrayQueue<(f32, f32)>>>,
+    analysis_queue: Option<Arc<ArrayQueue<f32>>>,
+    beat_clock: BeatClock,
+    meter: Arc<Meter>,
+    load: Arc<Load>,
+    rate: u32,
+    // the output fades out once set
+    fading_out: Arc<AtomicBool>,
 }
 
-impl Param {
-    fn new(sample_rate: f32, v: f32) -> Param {
-        Param {
-            v0: v,
-            v1: v,
-            counter: 1000,
-            smoothing: 1000,
-            sample_rate,
-        }
-    }
-    fn value(&mut self) -> f32 {
-        // if self.counter == self.smoothing {
-        //     self.v0 = self.v1;
-        //     return self.v0;
-        // }
-        // self.counter += 1;
-        // let v = self.v1 + (self.v0 - self.v1) * (((-(self.counter - self.smoothing) as f32 / self.sample_rate) / 0.01)).exp();
-        // println!("smooting {} ({} to {}) ({} to {})", v, self.v0, self.v1, self.counter, self.smoothing);
-        return self.v0;
-    }
-    fn set_value(&mut self, v: f32) {
-        //println!("set_value to {}", v);
-        //self.counter = 0;
-        //self.v1 = v;
-        self.v0 = v;
-    }
-    fn set_value_no_smooth(&mut self, v: f32) {
-        self.counter = self.smoothing;
-        self.v0 = v;
-        self.v1 = v;
+impl AudioThread {
+    // The notes still scheduled are dropped on panic.
+    fn apply(&mut self, m: Parameters) {
+        if let Parameters::Panic = m {
+            self.scheduled.retain(|&(_, p)| !matches!(p, Parameters::NoteOn(..)));
+        }
+        self.engine.set_parameter(m);
+    }
+    // Renders a buffer, whatever the number of channels of the stream.
+    fn process<F: OutputFrame>(&mut self, output: &mut [F]) {
+        let start = time::Instant::now();
+        if self.snapshot.read(&mut self.latest) {
+            for slot in 0..SNAPSHOT_SLOTS {
+                let v = self.latest[slot];
+                if v.to_bits() != self.applied[slot].to_bits() && !v.is_nan() {
+                    self.engine.set_parameter(snapshot_parameter(slot, v));
+                }
+            }
+            self.applied = self.latest;
+        }
+        let now = self.clock.raw_frames();
+        while let Ok((target, m)) = self.queue.pop() {
+            if target > now && self.scheduled.len() < self.scheduled.capacity() {
+                self.scheduled.push((target, m));
+            } else {
+                self.apply(m);
+            }
+        }
+        let mut peak: f32 = 0.;
+        let mut sum = 0.;
+        let mut reduction: f32 = 0.;
+        if self.fading_out.load(Ordering::Acquire) {
+            self.engine.fade_out();
+        }
+        for f in output.iter_mut() {
+            let t = self.clock.raw_frames();
+            // in the order they were sent
+            let mut i = 0;
+            while i < self.scheduled.len() {
+                if self.scheduled[i].0 <= t {
+                    let (_, m) = self.scheduled.remove(i);
+                    self.apply(m);
+                } else {
+                    i += 1;
+                }
+            }
+            let front = self.engine.render_frame();
+            let rear = self.engine.rear();
+            *f = F::new(front, rear);
+            peak = peak.max(front.l.abs()).max(front.r.abs());
+            sum += front.l * front.l + front.r * front.r;
+            reduction = reduction.max(self.engine.gain_reduction());
+            if let Some(ref rq) = self.record_queue {
+                let _ = rq.push((front.l, front.r));
+            }
+            if let Some(ref aq) = self.analysis_queue {
+                let _ = aq.push((front.l + front.r) / 2.);
+            }
+            self.updater.increment(1);
+        }
+        self.beat_clock.set_beat(self.engine.beat());
+        self.meter.update(peak, sum, 2 * output.len());
+        self.meter.set_gain_reduction(reduction);
+        self.load.update(start.elapsed(), output.len(), self.rate);
     }
 }
 
-struct Oscillator {
-    phase: f32,
-    sample_rate: f32,
-    frequency: Param,
-    detune: Param,
+// The last state of the stream, written by its state callback, read by the main thread.
+struct StreamStatus {
+    state: AtomicU32,
 }
 
-impl Oscillator {
-    fn new(sample_rate: f32) -> Oscillator  {
-        Oscillator {
-            phase: 0.,
-            frequency: Param::new(sample_rate, 440.),
-            sample_rate,
-            detune: Param::new(sample_rate, 0.),
+impl StreamStatus {
+    fn new() -> StreamStatus {
+        StreamStatus {
+            state: AtomicU32::new(cubeb::State::Stopped as u32),
         }
     }
-    fn render(&mut self, _t: usize) -> f32 {
-        let final_frequency = self.frequency.value() + (self.detune.value() / 1200.).exp2();
-        let period = self.sample_rate / final_frequency;
-        let phase_increment = 2. * PI / period;
-        let rv = self.phase.sin();
-        self.phase += phase_increment;
+    fn set(&self, state: cubeb::State) {
+        self.state.store(state as u32, Ordering::Release);
+    }
+    fn state(&self) -> cubeb::State {
+        match self.state.load(Ordering::Acquire) {
+            s if s == cubeb::State::Started as u32 => cubeb::State::Started,
+            s if s == cubeb::State::Drained as u32 => cubeb::State::Drained,
+            s if s == cubeb::State::Error as u32 => cubeb::State::Error,
+            _ => cubeb::State::Stopped,
+        }
+    }
+}
+
+// How many times a stream that failed is reopened, a second apart, before giving up.
+const STREAM_RETRIES: usize = 5;
+
+// Opens an output stream of `F` frames, on `device` or the default one, with
+// buffers of `latency` frames if the backend allows it. The audio thread outlives the
+// stream, so that a new stream continues where the previous one stopped: the lock is
+// only ever taken by the callback of a single stream.
+fn output_stream<F: OutputFrame>(
+    ctx: &cubeb::Context,
+    device: Option<cubeb::DeviceId>,
+    params: &cubeb::StreamParams,
+    latency: u32,
+    audio: Arc<Mutex<AudioThread>>,
+    status: Arc<StreamStatus>,
+) -> cubeb::Result<cubeb::Stream<F>> {
+    let mut builder = cubeb::StreamBuilder::<F>::new();
+    builder.name("redh").latency(latency);
+    match device {
+        Some(device) => builder.output(device, params),
+        None => builder.default_output(params),
+    };
+    builder
+        .data_callback(move |_, output| {
+            match audio.try_lock() {
+                Ok(mut audio) => audio.process(output),
+                Err(_) => {
+                    for f in output.iter_mut() {
+                        *f = F::new(Frame { l: 0., r: 0. }, (0., 0.));
+                    }
+                }
+            }
+            output.len() as isize
+        })
+        .state_callback(move |state| {
+            status.set(state);
+        });
+    builder.init(ctx)
+}
+
+// `output_stream`, with the frame type for `channels`.
+fn open_stream(
+    ctx: &cubeb::Context,
+    channels: u32,
+    device: Option<cubeb::DeviceId>,
+    params: &cubeb::StreamParams,
+    latency: u32,
+    audio: Arc<Mutex<AudioThread>>,
+    status: Arc<StreamStatus>,
+) -> cubeb::Result<OutputStream> {
+    match channels {
+        1 => output_stream(ctx, device, params, latency, audio, status).map(OutputStream::Mono),
+        4 => output_stream(ctx, device, params, latency, audio, status).map(OutputStream::Quad),
+        _ => output_stream(ctx, device, params, latency, audio, status).map(OutputStream::Stereo),
+    }
+}
 
-        if self.phase > 2. * PI {
-            self.phase -= 2. * PI;
+// The stream, whatever its frame type.
+enum OutputStream {
+    Mono(cubeb::Stream<MonoFrame<f32>>),
+    Stereo(cubeb::Stream<Frame>),
+    Quad(cubeb::Stream<QuadFrame>),
+}
+
+impl OutputStream {
+    fn start(&self) -> cubeb::Result<()> {
+        match self {
+            OutputStream::Mono(s) => s.start(),
+            OutputStream::Stereo(s) => s.start(),
+            OutputStream::Quad(s) => s.start(),
         }
-        if self.phase != self.phase {
-            self.phase = 0.;
+    }
+    fn stop(&self) -> cubeb::Result<()> {
+        match self {
+            OutputStream::Mono(s) => s.stop(),
+            OutputStream::Stereo(s) => s.stop(),
+            OutputStream::Quad(s) => s.stop(),
         }
-        return rv;
     }
-    fn set_frequency(&mut self, frequency: f32) {
-        self.frequency.set_value(frequency);
+    // In frames, as granted by the backend.
+    fn latency(&self) -> cubeb::Result<u32> {
+        match self {
+            OutputStream::Mono(s) => s.latency(),
+            OutputStream::Stereo(s) => s.latency(),
+            OutputStream::Quad(s) => s.latency(),
+        }
     }
-    fn set_frequency_no_smooth(&mut self, frequency: f32) {
-        self.frequency.set_value_no_smooth(frequency);
+}
+
+// Value following `name` on the command line, e.g. `--record out.wav`.
+fn arg_value(args: &[String], name: &str) -> Option<String> {
+    args.iter()
+        .position(|a| a == name)
+        .and_then(|i| args.get(i + 1))
+        .cloned()
+}
+
+// Number following `name` on the command line, if present.
+fn arg_f32(args: &[String], name: &str) -> Result<Option<f32>, String> {
+    match arg_value(args, name) {
+        Some(v) => match v.parse::<f32>() {
+            Ok(v) if v.is_finite() && v >= 0. => Ok(Some(v)),
+            _ => Err(format!("{}: expected a positive number, got {}", name, v)),
+        },
+        None if args.iter().any(|a| a == name) => Err(format!("{}: missing value", name)),
+        None => Ok(None),
+    }
+}
+
+// The initial parameters given on the command line override the patch.
+fn apply_args(args: &[String], patch: &mut Patch) -> Result<(), String> {
+    if let Some(v) = arg_f32(args, "--carrier")? {
+        patch.carrier_freq = v;
+    }
+    if let Some(v) = arg_f32(args, "--mod-freq")? {
+        patch.mod_freq = v;
+        patch.ratio = None;
+    }
+    if let Some(v) = arg_f32(args, "--attack")? {
+        patch.attack = v;
     }
-    // [-1200, 1200]
-    fn set_detune(&mut self, detune: f32) {
-        self.detune.set_value(detune);
+    if let Some(v) = arg_f32(args, "--release")? {
+        patch.release = v;
     }
-    fn set_phase(&mut self, phase: f32) {
-        // modulo 2 * PI ?
-        self.phase = phase;
+    match arg_f32(args, "--tuning")? {
+        Some(v) if v > 0. => patch.tuning = v,
+        None => {}
+        Some(v) => return Err(format!("--tuning: expected a positive number, got {}", v)),
+    }
+    if let Some(v) = arg_f32(args, "--oversample")? {
+        if v != 1. && v != 2. && v != 4. {
+            return Err(format!("--oversample: expected 1, 2 or 4, got {}", v));
+        }
+        patch.oversample = v as u8;
     }
+    if let Some(path) = arg_value(args, "--wavetable") {
+        patch.wavetable = Some(path);
+    }
+    if args.iter().any(|a| a == "--sine-table") {
+        patch.sine_table = true;
+    }
+    // `--sample <file.wav>`, once per sample
+    for w in args.windows(2).filter(|w| w[0] == "--sample") {
+        patch.samples.push(w[1].clone());
+    }
+    Ok(())
 }
 
-#[derive(Debug, Copy, Clone)]
-enum Parameters {
-    CarrierFreq(f32),
-    ModulationFreq(f32),
-    Attack(f32),
-    Release(f32),
+// All the monome devices that can be opened, the others are skipped with a warning.
+fn open_monomes() -> Vec<Monome> {
+    let devices = match Monome::enumerate_devices() {
+        Ok(devices) => devices,
+        Err(e) => {
+            let e = Error::MonomeConnect(format!("could not enumerate the devices ({})", e));
+            println!("Warning: {}", e);
+            return Vec::new();
+        }
+    };
+    devices
+        .iter()
+        .filter_map(|d| match Monome::from_device(d, "/prefix") {
+            Ok(monome) => Some(monome),
+            Err(e) => {
+                let e = Error::MonomeConnect(format!("could not open {} ({})", d, e));
+                println!("Warning: {}", e);
+                None
+            }
+        })
+        .collect()
 }
 
 fn main() {
-    let ctx = cubeb::init("redh").expect("Failed to create cubeb context");
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Error> {
+    let args: Vec<String> = env::args().collect();
+
+    let patch_path = arg_value(&args, "--patch").unwrap_or_else(|| "patch.toml".to_string());
+    let mut patch = match arg_value(&args, "--patch") {
+        Some(path) => load_patch(&path)?,
+        None => Patch::default(),
+    };
+    apply_args(&args, &mut patch).map_err(Error::Config)?;
+
+    // what the encoders control, the built-in mapping is mapping.toml
+    let mapping = match arg_value(&args, "--mapping") {
+        Some(path) => load_mapping(&path)?,
+        None => parse_mapping("mapping.toml", DEFAULT_MAPPING)?,
+    };
+
+    let song = match arg_value(&args, "--song") {
+        Some(path) => Some(load_song(&path)?),
+        None => None,
+    };
+
+    // `--offline <seconds> <file.wav>` renders without an audio device, as fast as
+    // possible
+    if let Some(i) = args.iter().position(|a| a == "--offline") {
+        let seconds = match arg_f32(&args, "--offline").map_err(Error::Config)? {
+            Some(v) => v,
+            None => unreachable!(),
+        };
+        let path = match args.get(i + 2) {
+            Some(path) => path,
+            None => return Err(Error::Config("--offline: missing the output file".to_string())),
+        };
+        let rate = SAMPLE_FREQUENCY;
+        let (mut engine, _) = startup_engine(&patch, song.as_ref(), rate)?;
+        for p in patch.parameters() {
+            engine.set_parameter(p);
+        }
+        engine.set_parameter(Parameters::Unison(1, 0.));
+        let frames = (seconds * rate as f32) as usize;
+        render_offline(&mut engine, frames, path, rate)
+            .map_err(|e| Error::Wav(path.clone(), e))?;
+        println!("Rendered {}s to {}", seconds, path);
+        return Ok(());
+    }
+
+    let ctx = cubeb::init("redh").map_err(Error::AudioInit)?;
+
+    // the device ids point into this, keep it alive as long as the stream
+    let devices = ctx
+        .enumerate_devices(cubeb::DeviceType::OUTPUT)
+        .map_err(Error::AudioInit)?;
+    if args.iter().any(|a| a == "--list-devices") {
+        for d in devices.iter() {
+            println!(
+                "{}: {}",
+                d.device_id().unwrap_or("?"),
+                d.friendly_name().unwrap_or("")
+            );
+        }
+        return Ok(());
+    }
+    // `--device` takes an id or a name from `--list-devices`
+    let device = arg_value(&args, "--device").and_then(|id| {
+        let found = devices
+            .iter()
+            .find(|d| d.device_id() == Some(&id) || d.friendly_name() == Some(&id));
+        if found.is_none() {
+            println!("Warning: no output device {}, using the default one", id);
+        }
+        found.map(|d| d.devid())
+    });
+    let rate = ctx.preferred_sample_rate().unwrap_or(SAMPLE_FREQUENCY);
+    // mono sums the output, quad has the effects alone in the rear
+    let channels = match arg_value(&args, "--channels").as_ref().map(|c| c.as_str()) {
+        None | Some("2") => 2,
+        Some("1") => 1,
+        Some("4") => 4,
+        Some(c) => {
+            let e = format!("Invalid --channels {}, expected 1, 2 or 4", c);
+            return Err(Error::Config(e));
+        }
+    };
+    let layout = match channels {
+        1 => MonoFrame::<f32>::LAYOUT,
+        4 => QuadFrame::LAYOUT,
+        _ => Frame::LAYOUT,
+    };
 
     let params = cubeb::StreamParamsBuilder::new()
         .format(STREAM_FORMAT)
-        .rate(SAMPLE_FREQUENCY)
-        .channels(2)
-        .layout(cubeb::ChannelLayout::STEREO)
+        .rate(rate)
+        .channels(channels)
+        .layout(layout)
         .take();
 
-    let (mut updater, consumer) = audio_clock(128., SAMPLE_FREQUENCY);
-    let _consumer2 = consumer.clone();
-    let mut env = ADSR::new(SAMPLE_FREQUENCY as f32);
-    let mut osc = Oscillator::new(SAMPLE_FREQUENCY as f32);
-    let mut osc_mod = Oscillator::new(SAMPLE_FREQUENCY as f32);
+    let (updater, consumer) = audio_clock(TEMPO, rate);
+    let clock = consumer.clone();
+    // about a buffer, the smallest the backend allows unless --latency-frames is given
+    let latency = match arg_value(&args, "--latency-frames").map(|l| l.parse::<u32>()) {
+        None => ctx.min_latency(&params).unwrap_or(512),
+        Some(Ok(l)) if l > 0 => l,
+        Some(_) => {
+            let e = "Invalid --latency-frames, expected a number of frames".to_string();
+            return Err(Error::Config(e));
+        }
+    };
+    let beat_clock = BeatClock::new();
+    let meter = Arc::new(Meter::new());
+    let load = Arc::new(Load::new());
+    let (engine, pattern) = startup_engine(&patch, song.as_ref(), rate)?;
 
-    let q = Arc::new(ArrayQueue::new(16));
-    let q2 = q.clone();
+    let q = Arc::new(ArrayQueue::new(64));
+    let snapshot = Arc::new(ParamSnapshot::new());
 
-    let mut builder = cubeb::StreamBuilder::<Frame>::new();
-    builder
-        .name("redh")
-        .default_output(&params)
-        .data_callback(move |_, output| {
-            match q2.pop() {
-                Ok(m) => {
-                    match m {
-                        Parameters::CarrierFreq(v) => {
-                            osc.set_frequency(v);
-                        }
-                        Parameters::ModulationFreq(v) => {
-                            osc_mod.set_frequency(v);
-                        }
-                        Parameters::Release(v) => {
-                            env.set_release(v);
-                        }
-                        Parameters::Attack(v) => {
-                            env.set_attack(v);
-                        }
-                    }
-                }
-                _ => { }
-            }
-            for f in output.iter_mut() {
-                let t = consumer.raw_frames();
-                if consumer.raw_frames() % 48000 == 0 {
-                    env.trigger(t);
-                }
-                let m = osc_mod.render(t);
-                osc.set_frequency_no_smooth((m + 1.0) * 100.);
-                let g = env.render(t);
-                let s = osc.render(t);
-                f.l = g * s;
-                f.r = f.l;
-                updater.increment(1);
-            }
-            output.len() as isize
-        })
-        .state_callback(|state| {
-            println!("stream {:?}", state);
-        });
+    // `--record-bits 16` records 16-bit integers, dithered with `--dither`
+    let format = match arg_value(&args, "--record-bits").as_ref().map(|b| b.as_str()) {
+        None | Some("32") => RecordFormat::Float32,
+        Some("16") => RecordFormat::Int16 {
+            dither: args.iter().any(|a| a == "--dither"),
+        },
+        Some(b) => {
+            let e = format!("Invalid --record-bits {}, expected 16 or 32", b);
+            return Err(Error::Config(e));
+        }
+    };
+    let recorder = match arg_value(&args, "--record") {
+        Some(path) => {
+            Some(Recorder::new(&path, rate, format).map_err(|e| Error::Wav(path, e))?)
+        }
+        None => None,
+    };
+    let record_queue = recorder.as_ref().map(|r| r.queue());
+    // with --analyze, the strongest partials of the output are printed every second
+    let analyzer = if args.iter().any(|a| a == "--analyze") {
+        Some(Analyzer::new(rate))
+    } else {
+        None
+    };
+    let analysis_queue = analyzer.as_ref().map(|a| a.queue());
+
+    // set on exit, the output fades out before the stream stops
+    let fading_out = Arc::new(AtomicBool::new(false));
+    let audio = AudioThread {
+        engine,
+        snapshot: snapshot.clone(),
+        latest: [f32::NAN; SNAPSHOT_SLOTS],
+        applied: [f32::NAN; SNAPSHOT_SLOTS],
+        queue: q.clone(),
+        scheduled: Vec::with_capacity(64),
+        clock: consumer,
+        updater,
+        record_queue,
+        analysis_queue,
+        beat_clock: beat_clock.clone(),
+        meter: meter.clone(),
+        load: load.clone(),
+        rate,
+        fading_out: fading_out.clone(),
+    };
+    let audio = Arc::new(Mutex::new(audio));
+    let status = Arc::new(StreamStatus::new());
+    let open = |device| {
+        open_stream(&ctx, channels, device, &params, latency, audio.clone(), status.clone())
+    };
+    let mut stream = open(device).map_err(Error::StreamBuild)?;
+    // backends may clamp the buffer size
+    match stream.latency() {
+        Ok(granted) if granted != latency => {
+            println!("Requested {} frames of latency, got {}", latency, granted);
+        }
+        Ok(_) => {}
+        Err(e) => println!("Warning: could not get the stream latency ({})", e),
+    }
 
-    let stream = builder.init(&ctx).expect("Failed to create cubeb stream");
+    // all the devices connected, arcs and grids, each arc showing its own page
+    let mut monomes = open_monomes();
+    if monomes.is_empty() {
+        println!("Warning: no monome, continuing without it");
+    }
 
-    let mut monome = Monome::new("/prefix".to_string()).unwrap();
+    let _midi = open_midi(q.clone());
+    let state = Arc::new(ParameterState::new());
+    let mut controls = Controls::new(q.clone(), snapshot, state.clone(), clock, latency as usize);
+    if let Some(addr) = arg_value(&args, "--listen") {
+        if let Err(e) = listen(&addr, q.clone(), state.clone()) {
+            println!("Warning: could not listen on {}", e);
+        }
+    }
+    match arg_value(&args, "--osc-port").map(|p| p.parse::<u16>()) {
+        Some(Ok(port)) => {
+            if let Err(e) = open_osc(port, q.clone(), state) {
+                println!("Warning: could not receive OSC on port {}", e);
+            }
+        }
+        Some(Err(e)) => return Err(Error::Config(format!("--osc-port: {}", e))),
+        None => {}
+    }
 
-    stream.start().unwrap();
+    stream.start().map_err(Error::Stream)?;
 
-    for i in 0..4 {
-        monome.ring_all(i, 0);
+    for monome in monomes.iter_mut() {
+        if monome.device_type() != MonomeDeviceType::Grid {
+            for i in 0..4 {
+                monome.ring_all(i, 0);
+            }
+        }
     }
 
-    let mut freq = 110.;
-    let mut mod_freq = 55.;
-    let mut attack = 0.1;
-    let mut release = 0.9;
-    let mut led = [0.; 4];
-    q.push(Parameters::CarrierFreq(freq)).unwrap();
-    q.push(Parameters::ModulationFreq(mod_freq)).unwrap();
-    q.push(Parameters::Attack(attack)).unwrap();
-    q.push(Parameters::Release(release)).unwrap();
+    // pressing two encoders at once saves the patch
+    let mut keys_down = vec![[false; 4]; monomes.len()];
+    let mut pages = vec![0; monomes.len()];
+    let mut led = initial_leds(&patch, &mapping, song.as_ref().and_then(|s| s.tempo));
+    let mut steps = [false; GRID_STEPS];
+    for (step, note) in steps.iter_mut().zip(pattern.iter()) {
+        *step = note.is_some();
+    }
+    // the playhead is the beat modulo this
+    let mut steps_length = pattern.len().max(1);
+    let mut playhead = GRID_STEPS;
+    // toggled by the key next to the save key of the grid
+    let mut drone = false;
+    // keys held on the grid keyboards, per MIDI note
+    let mut held_notes = [0u8; 128];
+    let mut meter_db = [-METER_RANGE; 2];
+    let mut meter_shown = vec![[0; 3]; monomes.len()];
+    // with --profile, the load of the audio thread is printed every second
+    let profile = args.iter().any(|a| a == "--profile");
+    let mut last_profile = time::Instant::now();
+    for p in patch.parameters() {
+        controls.send(p);
+    }
+    controls.send(Parameters::Unison(1, 0.));
+    // different variations each run, offline renders keep the default seed
+    let seed = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos());
+    controls.send(Parameters::HumanizeSeed(seed));
+    // the encoders where they were on exit, unless --no-restore
+    if !args.iter().any(|a| a == "--no-restore") && Path::new(ARC_STATE_PATH).exists() {
+        if let Err(e) =
+            restore_arc_state(ARC_STATE_PATH, &mut led, &mut patch, &mapping, &mut controls)
+        {
+            println!("Warning: could not restore the encoders ({})", e);
+        }
+    }
+    for monome in monomes.iter_mut() {
+        if monome.device_type() != MonomeDeviceType::Grid {
+            for i in 0..4 {
+                monome.ring_set(i, led[0][i] as u32, 3);
+            }
+        }
+    }
 
-    loop {
-        loop {
-            let e = monome.poll();
+    let running = Arc::new(AtomicBool::new(true));
+    let r = running.clone();
+    ctrlc::set_handler(move || {
+        r.store(false, Ordering::Release);
+    })?;
 
-            match e {
-                Some(MonomeEvent::EncoderDelta { n, delta }) => {
-                    let n = n as usize;
-                    monome.ring_set(n, led[n] as u32, 0);
-                    led[n] = led[n] + (delta as f32 / 10.);
-                    if led[n] < 0. {
-                        led[n] = 0.;
+    // The monome devices are polled on their own thread, that sends the changes as
+    // they arrive, and redraws the playhead and the meter every 10ms.
+    let control = {
+        let running = running.clone();
+        let beat_clock = beat_clock.clone();
+        let meter = meter.clone();
+        thread::spawn(move || {
+            let refresh = time::Duration::from_millis(10);
+            let mut last_draw = time::Instant::now();
+            while running.load(Ordering::Acquire) {
+                for (index, monome) in monomes.iter_mut().enumerate() {
+                    let mut page = pages[index];
+                    let keys_down = &mut keys_down[index];
+                    loop {
+                        let e = monome.poll();
+
+                        match e {
+                            Some(MonomeEvent::EncoderKey { n, direction: KeyDirection::Down }) => {
+                                if keys_down.iter().any(|&k| k) {
+                                    match save_patch(&patch_path, &patch) {
+                                        Ok(()) => println!("Saved {}", patch_path),
+                                        Err(e) => println!("Could not save the patch: {}", e),
+                                    }
+                                } else {
+                                    page = (page + 1) % PAGES;
+                                    for i in 0..4 {
+                                        monome.ring_all(i, 0);
+                                        monome.ring_set(i, led[page][i] as u32, 3);
+                                    }
+                                }
+                                keys_down[n % 4] = true;
+                            }
+                            Some(MonomeEvent::EncoderKey { n, direction: KeyDirection::Up }) => {
+                                keys_down[n % 4] = false;
+                            }
+                            Some(MonomeEvent::GridKey {
+                                x: 0,
+                                y: 0,
+                                direction: KeyDirection::Down,
+                            }) => {
+                                match save_patch(&patch_path, &patch) {
+                                    Ok(()) => println!("Saved {}", patch_path),
+                                    Err(e) => println!("Could not save the patch: {}", e),
+                                }
+                            }
+                            Some(MonomeEvent::GridKey {
+                                x: 2,
+                                y: 0,
+                                direction: KeyDirection::Down,
+                            }) => {
+                                if controls.automation().is_recording() {
+                                    controls.stop_recording();
+                                } else {
+                                    controls.stop_playback();
+                                    controls.start_recording();
+                                }
+                                draw_automation(monome, controls.automation());
+                            }
+                            Some(MonomeEvent::GridKey {
+                                x: 3,
+                                y: 0,
+                                direction: KeyDirection::Down,
+                            }) => {
+                                if controls.automation().is_playing() {
+                                    controls.stop_playback();
+                                } else if !controls.automation().is_recording() {
+                                    controls.start_playback();
+                                }
+                                draw_automation(monome, controls.automation());
+                            }
+                            Some(MonomeEvent::GridKey {
+                                x: 4,
+                                y: 0,
+                                direction: KeyDirection::Down,
+                            }) => {
+                                patch.freq_curve = match patch.freq_curve {
+                                    FreqCurve::Linear => FreqCurve::Exponential,
+                                    FreqCurve::Exponential => FreqCurve::Linear,
+                                };
+                                // the frequencies stay, the encoders move
+                                led[0][0] = freq_to_encoder(patch.carrier_freq, patch.freq_curve);
+                                led[0][1] = freq_to_encoder(patch.mod_freq, patch.freq_curve);
+                                let exponential = patch.freq_curve == FreqCurve::Exponential;
+                                monome.set(4, 0, if exponential { 15 } else { 0 });
+                            }
+                            Some(MonomeEvent::GridKey {
+                                x: 1,
+                                y: 0,
+                                direction: KeyDirection::Down,
+                            }) => {
+                                drone = !drone;
+                                controls.send(Parameters::Drone(drone));
+                                monome.set(1, 0, if drone { 15 } else { 0 });
+                            }
+                            Some(MonomeEvent::GridKey {
+                                x: 5,
+                                y: 0,
+                                direction: KeyDirection::Down,
+                            }) => {
+                                // the drone is closed and the keyboard released as well
+                                drone = false;
+                                held_notes = [0; 128];
+                                controls.send(Parameters::Panic);
+                                monome.set(1, 0, 0);
+                                draw_keyboard(monome, &held_notes);
+                            }
+                            Some(MonomeEvent::GridKey { x, y, direction: KeyDirection::Down }) => {
+                                let x = x as usize;
+                                if y == monome.height() as i32 - 1 && x < GRID_STEPS {
+                                    steps[x] = !steps[x];
+                                    steps_length = steps_length.max(x + 1);
+                                    controls.send(Parameters::ToggleStep(x));
+                                    draw_steps(monome, &steps, steps_length, playhead);
+                                } else if let Some(note) =
+                                    grid_note(x as i32, y, monome.height() as i32)
+                                {
+                                    // the same note can be held on several keys
+                                    held_notes[note as usize] += 1;
+                                    if held_notes[note as usize] == 1 {
+                                        let frequency = midi_to_freq(note);
+                                        controls.send(Parameters::NoteOn(0, frequency, 1.));
+                                    }
+                                    draw_keyboard(monome, &held_notes);
+                                }
+                            }
+                            Some(MonomeEvent::GridKey { x, y, direction: KeyDirection::Up }) => {
+                                if let Some(note) = grid_note(x, y, monome.height() as i32) {
+                                    let count = &mut held_notes[note as usize];
+                                    if *count > 0 {
+                                        *count -= 1;
+                                        if *count == 0 {
+                                            let frequency = midi_to_freq(note);
+                                            controls.send(Parameters::NoteOff(frequency));
+                                        }
+                                        draw_keyboard(monome, &held_notes);
+                                    }
+                                }
+                            }
+                            Some(MonomeEvent::EncoderDelta { .. }) if page == METER_PAGE => {}
+                            Some(MonomeEvent::EncoderDelta { n, delta }) => {
+                                let n = n as usize;
+                                let led = &mut led[page];
+                                monome.ring_set(n, led[n] as u32, 0);
+                                led[n] = led[n] + (delta as f32 / 10.);
+                                if led[n] < 0. {
+                                    led[n] = 0.;
+                                }
+                                if led[n] > 64. {
+                                    led[n] = 64.;
+                                }
+                                monome.ring_set(n, led[n] as u32, 3);
+                                turn_encoder(page, n, led, &mut patch, &mapping, &mut controls);
+                            }
+                            _ => {
+                                break;
+
+                            }
+                        }
                     }
-                    if led[n] > 64. {
-                        led[n] = 64.;
+                    pages[index] = page;
+                }
+
+                controls.play_automation();
+                let dropped = controls.take_dropped();
+                if dropped > 0 {
+                    println!("Warning: parameter queue full, dropped {} changes", dropped);
+                }
+
+                if last_draw.elapsed() >= refresh {
+                    last_draw = time::Instant::now();
+                    let beat = beat_clock.beat();
+                    let current = beat as usize % steps_length;
+                    let moved = current != playhead;
+                    playhead = current;
+                    let (peak, rms) = meter.levels();
+                    for (i, &level) in [peak, rms].iter().enumerate() {
+                        let db = 20. * level.max(1e-6).log10();
+                        meter_db[i] = db.max(meter_db[i] - METER_DECAY * 0.01);
                     }
-                    monome.ring_set(n, led[n] as u32, 3);
-                    match n {
-                        0 => {
-                            freq = led[0] * 10.;
-                            q.push(Parameters::CarrierFreq(freq)).unwrap();
-                        }
-                        1 => {
-                            mod_freq = led[1] * 10.;
-                            q.push(Parameters::ModulationFreq(mod_freq)).unwrap();
+                    for (index, monome) in monomes.iter_mut().enumerate() {
+                        if monome.device_type() == MonomeDeviceType::Grid {
+                            if moved {
+                                draw_steps(monome, &steps, steps_length, playhead);
+                            }
+                            continue;
                         }
-                        2 => {
-                            attack = led[2] / 64.;
-                            q.push(Parameters::Attack(attack)).unwrap();
+                        // peak on the first ring, RMS on the second one, gain reduction on
+                        // the third
+                        let shown = &mut meter_shown[index];
+                        if pages[index] == METER_PAGE {
+                            let reduction = (meter.gain_reduction() / GAIN_REDUCTION_RANGE).min(1.);
+                            let leds = [
+                                meter_leds(meter_db[0]),
+                                meter_leds(meter_db[1]),
+                                (reduction * 64.) as usize,
+                            ];
+                            for i in 0..3 {
+                                let leds = leds[i];
+                                if leds != shown[i] {
+                                    shown[i] = leds;
+                                    monome.ring_all(i, 0);
+                                    if leds > 0 {
+                                        monome.ring_range(i, 0, leds - 1, 8);
+                                    }
+                                }
+                            }
+                        } else {
+                            *shown = [0; 3];
                         }
-                        3 => {
-                            release = led[3] / 64.;
-                            q.push(Parameters::Release(release)).unwrap();
-                        }
-                        _ => {}
                     }
                 }
-                _ => {
-                    break;
 
+                thread::sleep(time::Duration::from_millis(1));
+            }
+            if let Err(e) = save_arc_state(ARC_STATE_PATH, &led) {
+                println!("Could not save the encoders: {}", e);
+            }
+            for monome in monomes.iter_mut() {
+                if monome.device_type() == MonomeDeviceType::Grid {
+                    monome.all(false);
+                } else {
+                    for i in 0..4 {
+                        monome.ring_all(i, 0);
+                    }
                 }
             }
+        })
+    };
+
+    // `--on-drain restart` restarts a stream that drained instead of exiting
+    let restart_on_drain = arg_value(&args, "--on-drain").map_or(false, |s| s == "restart");
+    while running.load(Ordering::Acquire) {
+        if profile && last_profile.elapsed() >= time::Duration::from_secs(1) {
+            last_profile = time::Instant::now();
+            println!("audio callback load: {:.1}%", load.load() * 100.);
         }
 
-        //println!("{} {} {} {}", freq, mod_freq, attack, release);
+        match status.state() {
+            cubeb::State::Error => {
+                // e.g. the device was unplugged, the default one is tried if it doesn't come
+                // back
+                println!("Stream error, reopening it");
+                drop(stream);
+                status.set(cubeb::State::Stopped);
+                audio.lock().unwrap().engine.fade_in();
+                let mut reopened = Err(cubeb::Error::error());
+                for attempt in 0..STREAM_RETRIES {
+                    let device = if attempt == 0 { device } else { None };
+                    let s = open(device).and_then(|s| s.start().map(|_| s));
+                    if let Err(ref e) = s {
+                        println!("Could not reopen the stream ({})", e);
+                    }
+                    reopened = s;
+                    if reopened.is_ok() {
+                        break;
+                    }
+                    thread::sleep(time::Duration::from_secs(1));
+                }
+                match reopened {
+                    Ok(s) => stream = s,
+                    Err(e) => {
+                        running.store(false, Ordering::Release);
+                        control.join().unwrap();
+                        return Err(Error::StreamBuild(e));
+                    }
+                }
+            }
+            cubeb::State::Drained if restart_on_drain => {
+                status.set(cubeb::State::Stopped);
+                if let Err(e) = stream.start() {
+                    println!("Could not restart the stream ({})", e);
+                }
+            }
+            cubeb::State::Drained => {
+                println!("Stream drained, exiting");
+                running.store(false, Ordering::Release);
+            }
+            cubeb::State::Started | cubeb::State::Stopped => {}
+        }
 
         let refresh = time::Duration::from_millis(10);
         thread::sleep(refresh);
     }
+
+    control.join().unwrap();
+    // the fade, and the buffers already queued
+    fading_out.store(true, Ordering::Release);
+    let fade = FADE_TIME + latency as f32 / rate as f32;
+    thread::sleep(time::Duration::from_secs_f32(2. * fade));
+    stream.stop().map_err(Error::Stream)?;
+    // a recording that failed stopped there
+    if let (Some(recorder), Some(path)) = (recorder, arg_value(&args, "--record")) {
+        recorder.finish().map_err(|e| Error::Wav(path, e))?;
+    }
+    Ok(())
 }
 
 
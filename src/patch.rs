@@ -0,0 +1,326 @@
+// Sounds and songs, loaded from files, and the engine they start.
+use crate::arc::FreqCurve;
+use crate::{
+    midi_to_freq, Engine, EnvelopeMode, Error, FilterType, FmMode, Parameters, Sample, Scale,
+    StealPolicy, VelCurve, Waveform, WavetableOsc, MAX_STEPS,
+};
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+// A sound, saved as TOML.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Patch {
+    pub carrier_freq: f32,
+    pub mod_freq: f32,
+    // when set, the modulator follows the carrier instead of `mod_freq`
+    pub ratio: Option<f32>,
+    pub mod_index: f32,
+    pub attack: f32,
+    pub release: f32,
+    pub decay: f32,
+    pub hold: f32,
+    pub sustain: f32,
+    pub envelope: EnvelopeMode,
+    // (seconds since the start of the note, level) points, of EnvelopeMode::Breakpoints
+    pub breakpoints: Vec<(f32, f32)>,
+    pub cutoff: f32,
+    pub resonance: f32,
+    pub filter_type: FilterType,
+    pub waveform: Waveform,
+    // 1, 2 or 4, for bright patches that alias
+    pub oversample: u8,
+    // path of the table of Waveform::Wavetable
+    pub wavetable: Option<String>,
+    // the sines are read from a table, cheaper for many voices
+    pub sine_table: bool,
+    // paths of the WAV files played by the notes of SAMPLE_CHANNEL
+    pub samples: Vec<String>,
+    pub steal_policy: StealPolicy,
+    // when set, notes start with the oscillators at this phase, in radians
+    pub retrigger_phase: Option<f32>,
+    // when set, the carrier frequency chosen on the arc snaps to this scale
+    pub scale: Option<Scale>,
+    pub scale_root: f32,
+    // of the carrier and modulator frequencies on the arc
+    pub freq_curve: FreqCurve,
+    // [0, 1], how much harder notes are brighter
+    pub vel_to_mod: f32,
+    pub vel_to_cutoff: f32,
+    pub velocity_curve: VelCurve,
+    pub fm_mode: FmMode,
+    pub sub_level: f32,
+    // [0, 1], random variations of each note
+    pub humanize: f32,
+    // the left and right sides of the voices are rendered apart
+    pub stereo_fm: bool,
+    pub stereo_spread: f32,
+    pub cross_mod: f32,
+    // envelope of the modulation index
+    pub mod_env_attack: f32,
+    pub mod_env_decay: f32,
+    pub mod_env_sustain: f32,
+    pub mod_env_release: f32,
+    pub mod_env_amount: f32,
+    // Hz, of A4
+    pub tuning: f32,
+}
+
+impl Default for Patch {
+    fn default() -> Patch {
+        Patch {
+            carrier_freq: 110.,
+            mod_freq: 55.,
+            ratio: None,
+            mod_index: 1.,
+            attack: 0.1,
+            release: 0.9,
+            decay: 0.,
+            hold: 0.,
+            sustain: 1.,
+            envelope: EnvelopeMode::Adsr,
+            breakpoints: Vec::new(),
+            cutoff: 20000.,
+            resonance: 0.,
+            filter_type: FilterType::LowPass,
+            waveform: Waveform::Sine,
+            oversample: 1,
+            wavetable: None,
+            sine_table: false,
+            samples: Vec::new(),
+            steal_policy: StealPolicy::Quietest,
+            retrigger_phase: None,
+            scale: None,
+            scale_root: 55.,
+            freq_curve: FreqCurve::Linear,
+            vel_to_mod: 0.,
+            vel_to_cutoff: 0.,
+            velocity_curve: VelCurve::Linear,
+            fm_mode: FmMode::Frequency,
+            sub_level: 0.,
+            humanize: 0.,
+            stereo_fm: false,
+            stereo_spread: 0.,
+            cross_mod: 0.,
+            mod_env_attack: 0.001,
+            mod_env_decay: 0.5,
+            mod_env_sustain: 1.,
+            mod_env_release: 0.9,
+            mod_env_amount: 0.,
+            tuning: 440.,
+        }
+    }
+}
+
+impl Patch {
+    pub fn parameters(&self) -> Vec<Parameters> {
+        let mut parameters = vec![
+            Parameters::CarrierFreq(self.carrier_freq),
+            Parameters::ModulationFreq(self.mod_freq),
+            Parameters::ModIndex(self.mod_index),
+            Parameters::Attack(self.attack),
+            Parameters::Release(self.release),
+            Parameters::Decay(self.decay),
+            Parameters::Hold(self.hold),
+            Parameters::Sustain(self.sustain),
+            Parameters::Cutoff(self.cutoff),
+            Parameters::Resonance(self.resonance),
+            Parameters::FilterType(self.filter_type),
+            Parameters::Waveform(self.waveform),
+            Parameters::Oversample(self.oversample),
+            Parameters::StealPolicy(self.steal_policy),
+            Parameters::RetriggerPhase(self.retrigger_phase),
+            Parameters::VelToMod(self.vel_to_mod),
+            Parameters::VelToCutoff(self.vel_to_cutoff),
+            Parameters::VelocityCurve(self.velocity_curve),
+            Parameters::FmMode(self.fm_mode),
+            Parameters::SubLevel(self.sub_level),
+            Parameters::Humanize(self.humanize),
+            Parameters::StereoFm(self.stereo_fm),
+            Parameters::StereoSpread(self.stereo_spread),
+            Parameters::CrossMod(self.cross_mod),
+            Parameters::ModEnvAttack(self.mod_env_attack),
+            Parameters::ModEnvDecay(self.mod_env_decay),
+            Parameters::ModEnvSustain(self.mod_env_sustain),
+            Parameters::ModEnvRelease(self.mod_env_release),
+            Parameters::ModEnvAmount(self.mod_env_amount),
+        ];
+        if let Some(ratio) = self.ratio {
+            parameters.push(Parameters::Ratio(ratio));
+        }
+        for (i, &(time, level)) in self.breakpoints.iter().enumerate() {
+            parameters.push(Parameters::Breakpoint(i, time, level));
+        }
+        parameters.push(Parameters::BreakpointCount(self.breakpoints.len()));
+        parameters.push(Parameters::EnvelopeMode(self.envelope));
+        return parameters;
+    }
+    // Follows a parameter set from a mapped encoder, so that it's saved.
+    pub fn set(&mut self, p: Parameters) {
+        match p {
+            Parameters::CarrierFreq(v) => self.carrier_freq = v,
+            Parameters::ModulationFreq(v) => {
+                self.mod_freq = v;
+                self.ratio = None;
+            }
+            Parameters::Ratio(v) => self.ratio = Some(v),
+            Parameters::ModIndex(v) => self.mod_index = v,
+            Parameters::Attack(v) => self.attack = v,
+            Parameters::Release(v) => self.release = v,
+            Parameters::Decay(v) => self.decay = v,
+            Parameters::Hold(v) => self.hold = v,
+            Parameters::Sustain(v) => self.sustain = v,
+            Parameters::EnvelopeMode(v) => self.envelope = v,
+            Parameters::Breakpoint(i, time, level) => {
+                if i < self.breakpoints.len() {
+                    self.breakpoints[i] = (time, level);
+                } else if i == self.breakpoints.len() {
+                    self.breakpoints.push((time, level));
+                }
+            }
+            Parameters::BreakpointCount(v) => self.breakpoints.truncate(v),
+            Parameters::Cutoff(v) => self.cutoff = v,
+            Parameters::Resonance(v) => self.resonance = v,
+            Parameters::FilterType(v) => self.filter_type = v,
+            Parameters::StealPolicy(v) => self.steal_policy = v,
+            Parameters::VelocityCurve(v) => self.velocity_curve = v,
+            Parameters::FmMode(v) => self.fm_mode = v,
+            Parameters::StereoFm(v) => self.stereo_fm = v,
+            Parameters::VelToMod(v) => self.vel_to_mod = v,
+            Parameters::VelToCutoff(v) => self.vel_to_cutoff = v,
+            Parameters::SubLevel(v) => self.sub_level = v,
+            Parameters::Humanize(v) => self.humanize = v,
+            Parameters::StereoSpread(v) => self.stereo_spread = v,
+            Parameters::CrossMod(v) => self.cross_mod = v,
+            Parameters::ModEnvAttack(v) => self.mod_env_attack = v,
+            Parameters::ModEnvDecay(v) => self.mod_env_decay = v,
+            Parameters::ModEnvSustain(v) => self.mod_env_sustain = v,
+            Parameters::ModEnvRelease(v) => self.mod_env_release = v,
+            Parameters::ModEnvAmount(v) => self.mod_env_amount = v,
+            _ => {}
+        }
+    }
+}
+
+pub fn load_patch(path: &str) -> Result<Patch, Error> {
+    let contents = fs::read_to_string(path).map_err(|e| Error::Io(path.to_string(), e))?;
+    toml::from_str(&contents).map_err(|e| Error::Config(format!("{}: {}", path, e)))
+}
+
+pub fn save_patch(path: &str, patch: &Patch) -> Result<(), Error> {
+    let contents = toml::to_string(patch).map_err(|e| Error::Config(e.to_string()))?;
+    fs::write(path, contents).map_err(|e| Error::Io(path.to_string(), e))
+}
+
+// A sequence of steps, in JSON, e.g.
+//
+// { "tempo": 120, "steps": [{ "note": 57, "parameters": [{ "Cutoff": 800 }] }, {}] }
+//
+// A step without a note is a rest. The parameters are written as for --listen.
+#[derive(Debug, Deserialize)]
+pub struct Song {
+    pub tempo: Option<f32>,
+    pub steps: Vec<SongStep>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct SongStep {
+    // MIDI note number
+    note: Option<u8>,
+    parameters: Vec<Parameters>,
+}
+
+impl Song {
+    pub fn pattern(&self) -> Vec<Option<f32>> {
+        self.steps.iter().map(|s| s.note.map(midi_to_freq)).collect()
+    }
+    pub fn step_parameters(&self) -> Vec<Vec<Parameters>> {
+        self.steps.iter().map(|s| s.parameters.clone()).collect()
+    }
+}
+
+// Errors point at the line and column, and show the line.
+pub fn load_song(path: &str) -> Result<Song, Error> {
+    let contents = fs::read_to_string(path).map_err(|e| Error::Io(path.to_string(), e))?;
+    let song: Song = serde_json::from_str(&contents).map_err(|e| {
+        let line = contents.lines().nth(e.line().saturating_sub(1)).unwrap_or("");
+        Error::Config(format!(
+            "{}:{}:{}: {}\n{}\n{:>column$}",
+            path,
+            e.line(),
+            e.column(),
+            e,
+            line,
+            "^",
+            column = e.column().max(1)
+        ))
+    })?;
+    // the sequencer needs at least a step to go through
+    if song.steps.is_empty() {
+        return Err(Error::Config(format!("{}: no steps", path)));
+    }
+    if song.steps.len() > MAX_STEPS {
+        return Err(Error::Config(format!(
+            "{}: {} steps, at most {} are supported",
+            path,
+            song.steps.len(),
+            MAX_STEPS
+        )));
+    }
+    Ok(song)
+}
+
+// The engine the audio thread starts from: the song if any, or the default pattern,
+// and the pattern the sequencer plays.
+pub fn startup_engine(
+    patch: &Patch,
+    song: Option<&Song>,
+    sample_rate: u32,
+) -> Result<(Engine, Vec<Option<f32>>), Error> {
+    let mut engine = Engine::new(sample_rate);
+    engine.set_tuning(patch.tuning);
+    let mut pattern = vec![
+        Some(110.),
+        None,
+        Some(110.),
+        Some(165.),
+        None,
+        Some(220.),
+        Some(165.),
+        None,
+    ];
+    // from the first frame, so that playing a song is reproducible
+    if let Some(song) = song {
+        pattern = song.pattern();
+        engine.set_step_parameters(song.step_parameters());
+        if let Some(tempo) = song.tempo {
+            engine.set_parameter(Parameters::Tempo(tempo));
+        }
+    }
+    engine.set_pattern(&pattern);
+    if let Some(ref path) = patch.wavetable {
+        let wavetable = WavetableOsc::load_table(path)?;
+        engine.set_wavetable(&wavetable);
+    }
+    engine.set_use_sine_table(patch.sine_table);
+    for path in patch.samples.iter() {
+        let sample = Sample::load(path)?;
+        engine.add_sample(&sample);
+    }
+    Ok((engine, pattern))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_song_without_steps_is_rejected() {
+        let path = std::env::temp_dir().join("redw-empty-song.json");
+        fs::write(&path, r#"{"steps": []}"#).unwrap();
+        let path = path.to_str().unwrap();
+        let error = load_song(path).unwrap_err();
+        assert_eq!(error.to_string(), format!("{}: no steps", path));
+    }
+}
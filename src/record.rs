@@ -0,0 +1,298 @@
+// Recording and analysis of the output, on their own threads, and offline rendering.
+use crate::{Engine, Noise, NOISE_SEED};
+use crossbeam::queue::ArrayQueue;
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::{thread, time};
+
+// Sample format of the recordings.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RecordFormat {
+    Float32,
+    // with TPDF dither and noise shaping, or simply rounded
+    Int16 { dither: bool },
+}
+
+// Quantizes to 16 bits, adding triangular dither of 2 LSB peak to peak, so that the
+// quantization error doesn't follow the signal, and subtracting the error of the
+// previous sample, so that the noise is pushed towards high frequencies, where it
+// is less audible.
+struct Dither {
+    noise: Noise,
+    error: f32,
+}
+
+impl Dither {
+    pub fn new(seed: u32) -> Dither {
+        Dither {
+            noise: Noise::new(seed),
+            error: 0.,
+        }
+    }
+    pub fn process(&mut self, x: f32) -> i16 {
+        let shaped = x * 32767. - self.error;
+        let tpdf = (self.noise.white() + self.noise.white()) / 2.;
+        let quantized = (shaped + tpdf).round().max(-32768.).min(32767.);
+        self.error = quantized - shaped;
+        quantized as i16
+    }
+}
+
+fn truncate_i16(x: f32) -> i16 {
+    (x * 32767.).round().max(-32768.).min(32767.) as i16
+}
+
+// Writes the frames the audio callback pushes to `queue` to a WAV file, on its own
+// thread so that the callback never does any I/O. The file is finalized by finish(), or
+// on drop. Writing stops at the first error, that finish() returns.
+pub struct Recorder {
+    queue: Arc<ArrayQueue<(f32, f32)>>,
+    running: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<Result<(), hound::Error>>>,
+}
+
+impl Recorder {
+    pub fn new(
+        path: &str,
+        sample_rate: u32,
+        format: RecordFormat,
+    ) -> Result<Recorder, hound::Error> {
+        let spec = match format {
+            RecordFormat::Float32 => hound::WavSpec {
+                channels: 2,
+                sample_rate,
+                bits_per_sample: 32,
+                sample_format: hound::SampleFormat::Float,
+            },
+            RecordFormat::Int16 { .. } => hound::WavSpec {
+                channels: 2,
+                sample_rate,
+                bits_per_sample: 16,
+                sample_format: hound::SampleFormat::Int,
+            },
+        };
+        let mut writer = hound::WavWriter::create(path, spec)?;
+        // one second of buffering
+        let queue = Arc::new(ArrayQueue::new(sample_rate as usize));
+        let running = Arc::new(AtomicBool::new(true));
+        let q = queue.clone();
+        let keep_running = running.clone();
+        let thread = thread::spawn(move || -> Result<(), hound::Error> {
+            // uncorrelated between the channels
+            let mut dither = [Dither::new(NOISE_SEED), Dither::new(NOISE_SEED.rotate_left(16))];
+            loop {
+                let stopping = !keep_running.load(Ordering::Acquire);
+                while let Ok((l, r)) = q.pop() {
+                    match format {
+                        RecordFormat::Float32 => {
+                            writer.write_sample(l)?;
+                            writer.write_sample(r)?;
+                        }
+                        RecordFormat::Int16 { dither: true } => {
+                            writer.write_sample(dither[0].process(l))?;
+                            writer.write_sample(dither[1].process(r))?;
+                        }
+                        RecordFormat::Int16 { dither: false } => {
+                            writer.write_sample(truncate_i16(l))?;
+                            writer.write_sample(truncate_i16(r))?;
+                        }
+                    }
+                }
+                if stopping {
+                    break;
+                }
+                // keep the header up to date in case the process is killed
+                writer.flush()?;
+                thread::sleep(time::Duration::from_millis(100));
+            }
+            writer.finalize()
+        });
+        Ok(Recorder {
+            queue,
+            running,
+            thread: Some(thread),
+        })
+    }
+    pub fn queue(&self) -> Arc<ArrayQueue<(f32, f32)>> {
+        self.queue.clone()
+    }
+    // Writes what is left in the queue, and finalizes the file.
+    pub fn finish(mut self) -> Result<(), hound::Error> {
+        self.stop()
+    }
+    fn stop(&mut self) -> Result<(), hound::Error> {
+        self.running.store(false, Ordering::Release);
+        match self.thread.take() {
+            Some(thread) => thread.join().unwrap(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop() {
+            println!("Warning: the recording failed ({})", e);
+        }
+    }
+}
+
+// Size of the analysis FFT, about 6Hz per bin at 48kHz, enough to tell the sidebands
+// of low modulator frequencies apart.
+const FFT_SIZE: usize = 8192;
+// number of partials printed, and how far below the strongest one they can be, in dB
+const PARTIALS: usize = 8;
+const PARTIALS_RANGE: f32 = 60.;
+
+// Prints the strongest partials of the output, about once a second, from the samples the
+// audio callback pushes to `queue`, on its own thread so that the callback never runs the
+// FFT.
+pub struct Analyzer {
+    queue: Arc<ArrayQueue<f32>>,
+    running: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Analyzer {
+    pub fn new(sample_rate: u32) -> Analyzer {
+        let queue = Arc::new(ArrayQueue::new(sample_rate as usize));
+        let running = Arc::new(AtomicBool::new(true));
+        let q = queue.clone();
+        let keep_running = running.clone();
+        let thread = thread::spawn(move || {
+            let fft = FftPlanner::new().plan_fft_forward(FFT_SIZE);
+            // Hann
+            let window: Vec<f32> = (0..FFT_SIZE)
+                .map(|i| 0.5 - 0.5 * (2. * PI * i as f32 / FFT_SIZE as f32).cos())
+                .collect();
+            let mut samples = Vec::with_capacity(FFT_SIZE);
+            let mut spectrum = vec![Complex::new(0., 0.); FFT_SIZE];
+            let mut last_print = time::Instant::now();
+            while keep_running.load(Ordering::Acquire) {
+                while let Ok(x) = q.pop() {
+                    samples.push(x);
+                    if samples.len() < FFT_SIZE {
+                        continue;
+                    }
+                    if last_print.elapsed() >= time::Duration::from_secs(1) {
+                        last_print = time::Instant::now();
+                        for (c, (x, w)) in spectrum.iter_mut().zip(samples.iter().zip(&window)) {
+                            *c = Complex::new(x * w, 0.);
+                        }
+                        fft.process(&mut spectrum);
+                        print_partials(&spectrum, sample_rate);
+                    }
+                    samples.clear();
+                }
+                thread::sleep(time::Duration::from_millis(10));
+            }
+        });
+        Analyzer {
+            queue,
+            running,
+            thread: Some(thread),
+        }
+    }
+    pub fn queue(&self) -> Arc<ArrayQueue<f32>> {
+        self.queue.clone()
+    }
+}
+
+impl Drop for Analyzer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap();
+        }
+    }
+}
+
+// The local maxima of the magnitude, strongest first, with the frequency refined by fitting
+// a parabola to the neighbouring bins. Levels are relative to a full scale sine.
+fn print_partials(spectrum: &[Complex<f32>], sample_rate: u32) {
+    let half = spectrum.len() / 2;
+    // the Hann window halves the amplitude
+    let scale = 4. / spectrum.len() as f32;
+    let db: Vec<f32> = spectrum[..half]
+        .iter()
+        .map(|c| 20. * (c.norm() * scale).max(1e-6).log10())
+        .collect();
+    let mut peaks: Vec<(f32, f32)> = (1..half - 1)
+        .filter(|&i| db[i] > db[i - 1] && db[i] >= db[i + 1])
+        .map(|i| {
+            let (a, b, c) = (db[i - 1], db[i], db[i + 1]);
+            let offset = 0.5 * (a - c) / (a - 2. * b + c);
+            let frequency = (i as f32 + offset) * sample_rate as f32 / spectrum.len() as f32;
+            (frequency, b - 0.25 * (a - c) * offset)
+        })
+        .collect();
+    peaks.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    let strongest = match peaks.first() {
+        Some(&(_, level)) => level,
+        None => {
+            println!("partials: silence");
+            return;
+        }
+    };
+    let partials: Vec<String> = peaks
+        .iter()
+        .take(PARTIALS)
+        .filter(|p| p.1 > strongest - PARTIALS_RANGE)
+        .map(|p| format!("{:.1}Hz {:.1}dB", p.0, p.1))
+        .collect();
+    println!("partials: {}", partials.join(", "));
+}
+
+// Renders `frames` frames to a WAV file, 32-bit float, stereo.
+pub fn render_offline(
+    engine: &mut Engine,
+    frames: usize,
+    path: &str,
+    sample_rate: u32,
+) -> Result<(), hound::Error> {
+    let spec = hound::WavSpec {
+        channels: 2,
+        sample_rate,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let mut writer = hound::WavWriter::create(path, spec)?;
+    for _ in 0..frames {
+        let f = engine.render_frame();
+        writer.write_sample(f.l)?;
+        writer.write_sample(f.r)?;
+    }
+    writer.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // dB relative to one LSB, of the strongest bin of the quantization error of a sine
+    // of `lsb` LSB peak, with a whole number of periods.
+    fn error_floor(lsb: f32, quantize: &mut dyn FnMut(f32) -> i16) -> f32 {
+        let n = 8192;
+        let mut error: Vec<Complex<f32>> = (0..n)
+            .map(|i| {
+                let x = lsb / 32767. * (2. * PI * 171. * i as f32 / n as f32).sin();
+                Complex::new(quantize(x) as f32 - x * 32767., 0.)
+            })
+            .collect();
+        FftPlanner::new().plan_fft_forward(n).process(&mut error);
+        let peak = error[..n / 2].iter().map(|c| c.norm()).fold(0., f32::max);
+        20. * (peak * 2. / n as f32).log10()
+    }
+
+    #[test]
+    fn dither_has_a_lower_error_floor_than_truncation() {
+        let mut dither = Dither::new(NOISE_SEED);
+        let dithered = error_floor(2.5, &mut |x| dither.process(x));
+        let truncated = error_floor(2.5, &mut truncate_i16);
+        // the error of the truncation is harmonics of the sine, the dither spreads it
+        assert!(dithered < truncated - 6., "{} vs {}", dithered, truncated);
+    }
+}